@@ -1,4 +1,3 @@
-use embed_resource;
 fn main() {
     #[cfg(target_os = "windows")]
     {
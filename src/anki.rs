@@ -0,0 +1,392 @@
+use crate::deck::{self, ColumnMapping, Deck, Diagnostic, RowPolicy};
+use csv::StringRecord;
+use rusqlite::{Connection, OpenFlags};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+pub const EXTENSIONS: [&str; 2] = ["apkg", "colpkg"];
+
+pub fn is_anki_package(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default();
+    EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NoteType {
+    pub id: i64,
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Note {
+    pub note_type: i64,
+    pub fields: Vec<String>,
+    /// Anki's own ID of the note, which stays the same when the note is edited.
+    pub guid: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Collection {
+    pub note_types: Vec<NoteType>,
+    pub notes: Vec<Note>,
+    /// The names of the files in the package's media folder.
+    pub media: BTreeSet<String>,
+}
+
+/// Reads the collection and the media list out of an Anki package.
+/// The SQLite database is unpacked into `work_dir` while it is read.
+pub fn read_package(path: &Path, work_dir: &Path) -> Result<Collection, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+
+    // Newer Anki versions put the real collection in collection.anki21b and
+    // leave a placeholder in collection.anki2 for older versions.
+    let database = work_dir.join("anki_collection.sqlite");
+    if let Ok(mut entry) = archive.by_name("collection.anki21b") {
+        zstd::stream::copy_decode(&mut entry, File::create(&database)?)?;
+    } else {
+        let name = ["collection.anki21", "collection.anki2"]
+            .into_iter()
+            .find(|name| archive.index_for_name(name).is_some())
+            .ok_or("The file is not an Anki package")?;
+        std::io::copy(&mut archive.by_name(name)?, &mut File::create(&database)?)?;
+    }
+
+    let mut collection = read_collection(&database)?;
+    let _ = std::fs::remove_file(&database);
+
+    if let Ok(mut entry) = archive.by_name("media") {
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes)?;
+        collection.media = read_media_names(&bytes)?;
+    }
+    Ok(collection)
+}
+
+fn read_collection(database: &Path) -> Result<Collection, Box<dyn std::error::Error>> {
+    let connection = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut collection = Collection::default();
+
+    let has_notetypes_table = connection
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'notetypes'")?
+        .exists([])?;
+    if has_notetypes_table {
+        let mut fields: HashMap<i64, Vec<String>> = HashMap::new();
+        let mut statement = connection.prepare("SELECT ntid, name FROM fields ORDER BY ntid, ord")?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (note_type, name) = row?;
+            fields.entry(note_type).or_default().push(name);
+        }
+        let mut statement = connection.prepare("SELECT id, name FROM notetypes ORDER BY id")?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+        for row in rows {
+            let (id, name) = row?;
+            collection.note_types.push(NoteType { id, name, fields: fields.remove(&id).unwrap_or_default() });
+        }
+    } else {
+        // Older collections keep the note types as JSON in the col table
+        let models: String = connection.query_row("SELECT models FROM col", [], |row| row.get(0))?;
+        let models: serde_json::Value = serde_json::from_str(&models)?;
+        for model in models.as_object().into_iter().flat_map(|models| models.values()) {
+            let mut fields: Vec<(i64, String)> = model["flds"].as_array()
+                .into_iter()
+                .flatten()
+                .map(|field| (field["ord"].as_i64().unwrap_or_default(), field["name"].as_str().unwrap_or_default().to_string()))
+                .collect();
+            fields.sort();
+            collection.note_types.push(NoteType {
+                id: model["id"].as_i64().or_else(|| model["id"].as_str()?.parse().ok()).unwrap_or_default(),
+                name: model["name"].as_str().unwrap_or_default().to_string(),
+                fields: fields.into_iter().map(|(_, name)| name).collect(),
+            });
+        }
+        collection.note_types.sort_by_key(|note_type| note_type.id);
+    }
+
+    let mut statement = connection.prepare("SELECT mid, flds, guid, tags FROM notes ORDER BY id")?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+    })?;
+    for row in rows {
+        let (note_type, fields, guid, tags) = row?;
+        collection.notes.push(Note {
+            note_type,
+            fields: fields.split('\u{1f}').map(|field| field.to_string()).collect(),
+            guid,
+            tags: deck::split_tags(&tags),
+        });
+    }
+    Ok(collection)
+}
+
+/// Reads the names of the media files. Legacy packages store them as JSON,
+/// newer ones as a zstd-compressed protobuf message.
+fn read_media_names(bytes: &[u8]) -> Result<BTreeSet<String>, Box<dyn std::error::Error>> {
+    if let Ok(map) = serde_json::from_slice::<HashMap<String, String>>(bytes) {
+        return Ok(map.into_values().collect());
+    }
+    let bytes = zstd::decode_all(bytes)?;
+    let mut names = BTreeSet::new();
+    // MediaEntries { repeated MediaEntry entries = 1; }, MediaEntry { string name = 1; ... }
+    for (field, entry) in protobuf_fields(&bytes) {
+        if field == 1 {
+            for (field, name) in protobuf_fields(entry) {
+                if field == 1 {
+                    names.insert(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Lists the length-delimited fields of a protobuf message, skipping the others.
+fn protobuf_fields(mut bytes: &[u8]) -> Vec<(u64, &[u8])> {
+    fn varint(bytes: &mut &[u8]) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = bytes.split_first()?;
+            *bytes = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    let mut fields = Vec::new();
+    while let Some(key) = varint(&mut bytes) {
+        let skip = match key & 7 {
+            0 => {
+                if varint(&mut bytes).is_none() {
+                    break;
+                }
+                0
+            }
+            1 => 8,
+            5 => 4,
+            2 => {
+                let Some(length) = varint(&mut bytes) else {
+                    break;
+                };
+                let length = length as usize;
+                if length > bytes.len() {
+                    break;
+                }
+                fields.push((key >> 3, &bytes[..length]));
+                length
+            }
+            _ => break,
+        };
+        if skip > bytes.len() {
+            break;
+        }
+        bytes = &bytes[skip..];
+    }
+    fields
+}
+
+/// Loads the notes of an Anki package as cards. Each note type has its own
+/// mapping from field names to card sides, falling back to the first field on
+/// the front and the second on the back. Notes with cloze deletions produce one
+/// card per cloze number.
+pub fn load_anki(path: &Path, mappings: &HashMap<String, ColumnMapping>, policy: RowPolicy, work_dir: &Path) -> Result<Deck, Box<dyn std::error::Error>> {
+    let collection = read_package(path, work_dir)?;
+    let mut deck = Deck::default();
+
+    for note_type in &collection.note_types {
+        let headers: StringRecord = note_type.fields.iter().collect();
+        let mapping = mappings.get(&note_type.name).cloned().unwrap_or_default();
+        let mut records = Vec::new();
+        for (index, note) in collection.notes.iter().enumerate() {
+            if note.note_type != note_type.id {
+                continue;
+            }
+            let row = index + 1;
+            let mut fields = Vec::new();
+            for field in &note.fields {
+                let (markup, problems) = html_to_markup(field, &collection.media);
+                deck.diagnostics.extend(problems.into_iter().map(|message| Diagnostic::new(row, message)));
+                fields.push(markup);
+            }
+            records.push(Ok((row, fields.iter().collect::<StringRecord>())));
+        }
+        let mut note_deck = deck::cards_from_records(Some(&headers), records, &mapping, policy, false)?;
+        for card in note_deck.cards {
+            // The note's guid and tags come with it, rather than from a field
+            let note = &collection.notes[card.row - 1];
+            let card = deck::Card {
+                id: if card.id.is_empty() { note.guid.clone() } else { card.id },
+                tags: card.tags.into_iter().chain(note.tags.iter().cloned()).collect(),
+                ..card
+            };
+            deck.cards.extend(expand_clozes(card));
+        }
+        deck.diagnostics.append(&mut note_deck.diagnostics);
+    }
+
+    deck.cards.sort_by_key(|card| card.row);
+    deck.diagnostics.sort_by_key(|diagnostic| diagnostic.row);
+    Ok(deck)
+}
+
+/// Turns a card containing `{{c1::answer::hint}}` deletions into one card per
+/// cloze number. The front hides the current deletion, the back shows it underlined,
+/// followed by whatever was mapped to the back. Each card's ID ends in its cloze number.
+fn expand_clozes(card: deck::Card) -> Vec<deck::Card> {
+    let numbers: BTreeSet<usize> = clozes(&card.front).into_iter()
+        .chain(clozes(&card.back))
+        .map(|cloze| cloze.number)
+        .collect();
+    if numbers.is_empty() {
+        return vec![card];
+    }
+    let front_has_clozes = !clozes(&card.front).is_empty();
+    numbers.into_iter()
+        .map(|number| {
+            let mut back = render_clozes(&card.back, number, true);
+            if front_has_clozes {
+                back = format!("{} {}", render_clozes(&card.front, number, true), back).trim().to_string();
+            }
+            deck::Card {
+                front: render_clozes(&card.front, number, false),
+                back,
+                id: if card.id.is_empty() { String::new() } else { format!("{}-c{}", card.id, number) },
+                ..card.clone()
+            }
+        })
+        .collect()
+}
+
+struct Cloze<'a> {
+    start: usize,
+    end: usize,
+    number: usize,
+    answer: &'a str,
+    hint: Option<&'a str>,
+}
+
+fn clozes(text: &str) -> Vec<Cloze<'_>> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find("{{c").map(|i| i + offset) {
+        let Some(end) = text[start..].find("}}").map(|i| i + start + 2) else {
+            break;
+        };
+        let inner = &text[start + 3..end - 2];
+        if let Some((number, rest)) = inner.split_once("::")
+            && let Ok(number) = number.parse()
+        {
+            let (answer, hint) = match rest.split_once("::") {
+                Some((answer, hint)) => (answer, Some(hint)),
+                None => (rest, None),
+            };
+            found.push(Cloze { start, end, number, answer, hint });
+        }
+        offset = end;
+    }
+    found
+}
+
+fn render_clozes(text: &str, current: usize, reveal: bool) -> String {
+    let mut output = String::new();
+    let mut offset = 0;
+    for cloze in clozes(text) {
+        output.push_str(&text[offset..cloze.start]);
+        if cloze.number != current {
+            output.push_str(cloze.answer);
+        } else if reveal {
+            output.push_str(&format!("<u>{}</u>", cloze.answer));
+        } else {
+            output.push_str(&format!("[{}]", cloze.hint.unwrap_or("...")));
+        }
+        offset = cloze.end;
+    }
+    output.push_str(&text[offset..]);
+    output
+}
+
+/// Converts the HTML Anki stores in a field into the inline markup that
+/// `textutils::tokenize_html` reads. Formatting tags are kept under their
+/// short names, `span` and `font` with their attributes, line breaks and block elements become `<br>` and everything
+/// else is dropped.
+/// Returns the markup together with a note about anything that can't be printed.
+pub fn html_to_markup(html: &str, media: &BTreeSet<String>) -> (String, Vec<String>) {
+    let mut output = String::new();
+    let mut problems = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find(['<', '[', '&']) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("&nbsp;") {
+            output.push(' ');
+            rest = &rest["&nbsp;".len()..];
+        } else if rest.starts_with("[sound:")
+            && let Some(end) = rest.find(']')
+        {
+            problems.push(format!("the sound {} can't be printed", &rest["[sound:".len()..end]));
+            rest = &rest[end + 1..];
+        } else if rest.starts_with('<')
+            && let Some(end) = rest.find('>')
+        {
+            let tag = &rest[1..end];
+            let is_closing = tag.starts_with('/');
+            let name: String = tag.trim_start_matches('/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            let short_name = match name.as_str() {
+                "b" | "strong" => Some("b"),
+                "i" | "em" => Some("i"),
+                "u" => Some("u"),
+                "sub" => Some("sub"),
+                "sup" => Some("sup"),
+                "s" | "strike" | "del" => Some("s"),
+                "mark" => Some("mark"),
+                _ => None,
+            };
+            if let Some(short_name) = short_name {
+                output.push_str(&format!("<{}{}>", if is_closing { "/" } else { "" }, short_name));
+            } else if matches!(name.as_str(), "span" | "font") {
+                // Kept with their attributes, for colours and font sizes
+                output.push_str(&format!("<{}>", tag));
+            } else if name == "br" {
+                output.push_str("<br>");
+            } else if matches!(name.as_str(), "div" | "p" | "li" | "tr") {
+                // Blocks start on a line of their own
+                if !output.trim_end().is_empty() && !output.trim_end().ends_with("<br>") {
+                    output.push_str("<br>");
+                }
+            } else if name == "img" {
+                let source = tag.split_once("src=")
+                    .map(|(_, source)| source.trim_start_matches(['"', '\'']))
+                    .and_then(|source| source.split(['"', '\'', ' ']).next())
+                    .unwrap_or_default();
+                if media.contains(source) {
+                    problems.push(format!("the image {} can't be printed", source));
+                } else {
+                    problems.push(format!("the image {} is missing from the package", source));
+                }
+            }
+            rest = &rest[end + 1..];
+        } else {
+            output.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    let mut markup = output.trim();
+    while let Some(trimmed) = markup.strip_prefix("<br>").or_else(|| markup.strip_suffix("<br>")) {
+        markup = trimmed.trim();
+    }
+    (markup.to_string(), problems)
+}
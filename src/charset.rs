@@ -0,0 +1,92 @@
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+use std::path::Path;
+
+/// The encodings offered in the GUI. `None` means detect the encoding from the file.
+pub const ENCODINGS: [(Option<&Encoding>, &str); 11] = [
+    (None, "Detect automatically"),
+    (Some(encoding_rs::UTF_8), "UTF-8"),
+    (Some(encoding_rs::UTF_16LE), "UTF-16 LE (Excel \"Unicode Text\")"),
+    (Some(encoding_rs::UTF_16BE), "UTF-16 BE"),
+    (Some(encoding_rs::WINDOWS_1252), "Windows-1252 (Western European)"),
+    (Some(encoding_rs::WINDOWS_1250), "Windows-1250 (Central European)"),
+    (Some(encoding_rs::WINDOWS_1251), "Windows-1251 (Cyrillic)"),
+    (Some(encoding_rs::ISO_8859_15), "ISO-8859-15"),
+    (Some(encoding_rs::SHIFT_JIS), "Shift_JIS"),
+    (Some(encoding_rs::GBK), "GBK"),
+    (Some(encoding_rs::EUC_KR), "EUC-KR"),
+];
+
+/// Reads a text file, decoding it with `encoding` or, if that is `None`,
+/// with the encoding detected from its byte order mark or contents.
+/// Returns the text together with the encoding that was used.
+pub fn read_text(path: &Path, encoding: Option<&'static Encoding>) -> Result<(String, &'static Encoding), Box<dyn std::error::Error>> {
+    decode(&std::fs::read(path)?, encoding)
+        .map_err(|_| format!("{} is not valid UTF-8, try choosing its encoding", path.display()).into())
+}
+
+/// Decodes text like `read_text`, failing only if UTF-8 text has invalid bytes.
+pub fn decode(bytes: &[u8], encoding: Option<&'static Encoding>) -> Result<(String, &'static Encoding), Box<dyn std::error::Error>> {
+    let encoding = encoding.unwrap_or_else(|| detect(bytes));
+    let (text, had_errors) = encoding.decode_with_bom_removal(bytes);
+    if had_errors && encoding == encoding_rs::UTF_8 {
+        return Err("The text is not valid UTF-8".into());
+    }
+    Ok((text.into_owned(), encoding))
+}
+
+/// Guesses the encoding of a file, trusting a byte order mark if there is one.
+pub fn detect(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+
+    // UTF-16 without a BOM: mostly-ASCII text has a zero in every other byte
+    let sample = &bytes[..bytes.len().min(4096)];
+    let zeros_at = |parity: usize| sample.iter().skip(parity).step_by(2).filter(|&&b| b == 0).count();
+    let pairs = sample.len() / 2;
+    if pairs > 0 {
+        if zeros_at(1) * 10 > pairs * 3 && zeros_at(0) * 10 < pairs {
+            return encoding_rs::UTF_16LE;
+        }
+        if zeros_at(0) * 10 > pairs * 3 && zeros_at(1) * 10 < pairs {
+            return encoding_rs::UTF_16BE;
+        }
+    }
+
+    if std::str::from_utf8(bytes).is_ok() {
+        return encoding_rs::UTF_8;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    #[test]
+    fn detect_trusts_a_byte_order_mark() {
+        assert_eq!(detect(b"\xef\xbb\xbffront,back\n"), encoding_rs::UTF_8);
+        assert_eq!(detect(b"\xff\xfef\0"), encoding_rs::UTF_16LE);
+        assert_eq!(detect(b"\xfe\xff\0f"), encoding_rs::UTF_16BE);
+    }
+
+    #[test]
+    fn detect_utf16_without_a_byte_order_mark() {
+        assert_eq!(detect(&utf16("front,back\nHund,dog\n", u16::to_le_bytes)), encoding_rs::UTF_16LE);
+        assert_eq!(detect(&utf16("front,back\nHund,dog\n", u16::to_be_bytes)), encoding_rs::UTF_16BE);
+    }
+
+    #[test]
+    fn detect_utf8_and_legacy_encodings() {
+        assert_eq!(detect("Straße,street\ncafé,café\n".as_bytes()), encoding_rs::UTF_8);
+        let (bytes, _, _) = encoding_rs::WINDOWS_1252.encode("front,back\nStraße,street\ncafé,coffee shop\nnaïve,naive\n");
+        assert_eq!(detect(&bytes), encoding_rs::WINDOWS_1252);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::deck::{CardFilter, CopyPlacement, OverflowPolicy, RowPolicy};
+use crate::textutils::Markup;
+use crate::{PageLayout, PAPER_SIZES};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: facade [DECK...]
+       facade --output FILE.pdf [OPTIONS] [DECK... | -]
+
+Without --output the window opens with the decks loaded. With --output the
+flashcards are written to FILE.pdf without opening a window. A deck named -,
+or no deck at all, is read from stdin as tab- or comma-separated text.
+
+Options:
+  -o, --output FILE    write the flashcards to FILE
+      --no-header      the decks have no header row
+      --paper SIZE     A4, Letter, Legal or Tabloid (default Letter)
+      --page WxH       page size in millimetres, for example 210x297
+      --grid COLSxROWS flashcards on each page (default 3x4)
+      --no-flip        don't mirror the backs for printers that flip pages
+      --tag TAG        only print the cards with this tag
+      --ids ID,...     only print the cards with these IDs, to reprint lost cards
+      --print-ids      print each card's ID in its corner
+      --spread-copies  put the copies of a card on different sheets
+      --language CODE  hyphenate long words in this language, for example de
+      --markup WHAT    formatting in the text: html (default), markdown for
+                       **bold** and the like, or plain for none
+      --fit MIN-MAX    fit the font size of each card between MIN and MAX points
+      --overflow WHAT  text too long for a card: warn (default), continue on
+                       extra cards, or stop
+      --bad-rows WHAT  rows with missing or extra fields: blank (default) to
+                       print a blank side, skip, or stop
+  -h, --help           show this help";
+
+/// What the command line asks Facade to do.
+pub enum Command {
+    /// Open the window with these decks.
+    Gui(Vec<PathBuf>),
+    /// Write the flashcards without opening the window.
+    Generate(Options),
+    Help,
+}
+
+pub struct Options {
+    /// The decks in print order. `-` means stdin.
+    pub decks: Vec<String>,
+    pub output: PathBuf,
+    pub has_header: bool,
+    /// The ISO 639-1 code of the language the decks are hyphenated in, or empty.
+    pub language: String,
+    /// How formatting is written in the text of the decks.
+    pub markup: Markup,
+    /// What to do with rows that don't match the header.
+    pub row_policy: RowPolicy,
+    pub filter: CardFilter,
+    pub layout: PageLayout,
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut decks = Vec::new();
+    let mut output = None;
+    let mut has_header = true;
+    let mut filter = CardFilter::default();
+    let mut language = String::new();
+    let mut markup = Markup::Html;
+    let mut row_policy = RowPolicy::Blank;
+    let mut layout = PageLayout {
+        page_width: 215.9,
+        page_height: 279.4,
+        num_cards_width: 3,
+        num_cards_height: 4,
+        flip_horizontal: true,
+        flip_vertical: false,
+        print_ids: false,
+        copies: CopyPlacement::Together,
+        fit_font_size: None,
+        overflow: OverflowPolicy::Warn,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => output = Some(PathBuf::from(value(&arg)?)),
+            "--no-header" => has_header = false,
+            "--no-flip" => layout.flip_horizontal = false,
+            "--tag" => filter.tag = value(&arg)?,
+            "--ids" => filter.ids = value(&arg)?,
+            "--print-ids" => layout.print_ids = true,
+            "--spread-copies" => layout.copies = CopyPlacement::Spread,
+            "--language" => {
+                language = value(&arg)?;
+                if crate::hyphenation::language(&language).is_none() {
+                    return Err(format!("There are no hyphenation patterns for the language \"{}\"", language));
+                }
+            }
+            "--markup" => {
+                markup = match value(&arg)?.to_ascii_lowercase().as_str() {
+                    "html" => Markup::Html,
+                    "markdown" => Markup::Markdown,
+                    "plain" => Markup::Plain,
+                    other => return Err(format!("--markup must be html, markdown or plain, not \"{}\"", other)),
+                };
+            }
+            "--overflow" => {
+                layout.overflow = match value(&arg)?.to_ascii_lowercase().as_str() {
+                    "warn" => OverflowPolicy::Warn,
+                    "continue" => OverflowPolicy::Continue,
+                    "stop" => OverflowPolicy::Abort,
+                    other => return Err(format!("--overflow must be warn, continue or stop, not \"{}\"", other)),
+                };
+            }
+            "--bad-rows" => {
+                row_policy = match value(&arg)?.to_ascii_lowercase().as_str() {
+                    "skip" => RowPolicy::Skip,
+                    "blank" => RowPolicy::Blank,
+                    "stop" => RowPolicy::Abort,
+                    other => return Err(format!("--bad-rows must be skip, blank or stop, not \"{}\"", other)),
+                };
+            }
+            "--fit" => {
+                let sizes = value(&arg)?;
+                let (min, max) = sizes.split_once('-')
+                    .and_then(|(min, max)| Some((min.trim().parse::<f64>().ok()?, max.trim().parse::<f64>().ok()?)))
+                    .filter(|(min, max)| *min > 0.0 && min <= max)
+                    .ok_or_else(|| format!("\"{}\" is not a range of font sizes like 8-40", sizes))?;
+                layout.fit_font_size = Some((min, max));
+            }
+            "--paper" => {
+                let paper = value(&arg)?;
+                let (_, width, height) = PAPER_SIZES.iter()
+                    .find(|(name, _, _)| name.eq_ignore_ascii_case(&paper))
+                    .ok_or_else(|| format!("Unknown paper size \"{}\"", paper))?;
+                layout.page_width = *width;
+                layout.page_height = *height;
+            }
+            "--page" => {
+                let (width, height) = dimensions::<f64>(&value(&arg)?)?;
+                if !(width > 0.0 && height > 0.0) {
+                    return Err("The page must be wider and taller than 0mm".to_string());
+                }
+                layout.page_width = width;
+                layout.page_height = height;
+            }
+            "--grid" => {
+                let (columns, rows) = dimensions::<i32>(&value(&arg)?)?;
+                if columns < 1 || rows < 1 {
+                    return Err("The grid needs at least one row and one column".to_string());
+                }
+                layout.num_cards_width = columns;
+                layout.num_cards_height = rows;
+            }
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option {}", arg)),
+            _ => decks.push(arg),
+        }
+    }
+
+    let Some(output) = output else {
+        if decks.iter().any(|deck| deck == "-") {
+            return Err("Reading a deck from stdin needs --output".to_string());
+        }
+        return Ok(Command::Gui(decks.into_iter().map(PathBuf::from).collect()));
+    };
+    if decks.is_empty() {
+        decks.push("-".to_string());
+    }
+    if decks.iter().filter(|deck| *deck == "-").count() > 1 {
+        return Err("stdin can only be read once".to_string());
+    }
+    Ok(Command::Generate(Options { decks, output, has_header, language, markup, row_policy, filter, layout }))
+}
+
+/// Parses a size such as `210x297`.
+fn dimensions<T: std::str::FromStr>(text: &str) -> Result<(T, T), String> {
+    text.split_once(['x', 'X'])
+        .and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?)))
+        .ok_or_else(|| format!("\"{}\" is not a size like 210x297", text))
+}
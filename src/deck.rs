@@ -0,0 +1,670 @@
+use crate::dialect::Dialect;
+use crate::directive::Directive;
+use csv::StringRecord;
+use std::collections::HashMap;
+use std::fmt;
+
+/// One flashcard, with both sides kept together.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Card {
+    pub front: String,
+    pub back: String,
+    /// The row of the source file the card came from, starting at 1.
+    pub row: usize,
+    /// Identifies the card across print runs: the deck's ID column, or else
+    /// derived from the text by `assign_ids`.
+    pub id: String,
+    pub tags: Vec<String>,
+    pub style: CardStyle,
+    /// Start a new sheet with this card.
+    pub page_break: bool,
+    /// How many copies are printed, one if `None`.
+    pub quantity: Option<usize>,
+}
+
+/// Overrides of how one card is printed. `None` keeps the deck's setting.
+#[derive(Debug, PartialEq, Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CardStyle {
+    pub font: Option<String>,
+    /// Font size of the front in points.
+    pub front_size: Option<f64>,
+    /// Font size of the back in points.
+    pub back_size: Option<f64>,
+    /// The language long words are hyphenated in, as an ISO 639-1 code such as `de`.
+    pub language: Option<String>,
+}
+
+/// What to do with a row that has fewer or more fields than the header.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RowPolicy {
+    /// Leave the row out of the deck.
+    Skip,
+    /// Keep the row, printing a blank side where the fields are missing.
+    Blank,
+    /// Stop loading the deck.
+    Abort,
+}
+
+/// What to do with a card whose text is too long to fit on it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OverflowPolicy {
+    /// Print the card anyway and warn about it.
+    Warn,
+    /// Print the text that fits, and the rest on extra cards marked "(cont.)".
+    Continue,
+    /// Stop generating the flashcards.
+    Abort,
+}
+
+/// A problem found in one row of a deck.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    /// The file the row is in, when several files are printed together.
+    pub file: Option<String>,
+    /// The row of the card, or 0 for cards that aren't from the file, such as dividers.
+    pub row: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(row: usize, message: String) -> Self {
+        Diagnostic { file: None, row, message }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.row) {
+            (Some(file), 0) => write!(f, "{}: {}", file, self.message),
+            (Some(file), row) => write!(f, "{}, row {}: {}", file, row, self.message),
+            (None, 0) => write!(f, "{}", self.message),
+            (None, row) => write!(f, "Row {}: {}", row, self.message),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Deck {
+    pub cards: Vec<Card>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A column of the deck, either by position (starting at 0) or by header name.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ColumnRef {
+    Index(usize),
+    Name(String),
+}
+
+impl ColumnRef {
+    /// Finds the position of the column, using the header row to look up names.
+    pub fn resolve(&self, headers: Option<&StringRecord>) -> Option<usize> {
+        match self {
+            ColumnRef::Index(index) => Some(*index),
+            ColumnRef::Name(name) => headers?
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name.trim())),
+        }
+    }
+}
+
+impl From<usize> for ColumnRef {
+    fn from(index: usize) -> Self {
+        ColumnRef::Index(index)
+    }
+}
+
+impl From<&str> for ColumnRef {
+    fn from(name: &str) -> Self {
+        ColumnRef::Name(name.to_string())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Side {
+    Front,
+    Back,
+    /// The card's tags, separated by commas, semicolons or spaces.
+    Tags,
+    /// The card's ID.
+    Id,
+    /// How many copies of the card are printed.
+    Quantity,
+    Unused,
+}
+
+/// Which columns end up on the front and on the back of a card.
+/// Several columns on the same side are joined with `separator`, in the order they are listed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ColumnMapping {
+    pub front: Vec<ColumnRef>,
+    pub back: Vec<ColumnRef>,
+    pub tags: Option<ColumnRef>,
+    pub id: Option<ColumnRef>,
+    pub quantity: Option<ColumnRef>,
+    pub separator: String,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            front: vec![ColumnRef::Index(0)],
+            back: vec![ColumnRef::Index(1)],
+            tags: None,
+            id: None,
+            quantity: None,
+            separator: " ".to_string(),
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Returns the side a column at `index` is printed on.
+    pub fn side(&self, index: usize, headers: Option<&StringRecord>) -> Side {
+        let matches = |column: &ColumnRef| column.resolve(headers) == Some(index);
+        if self.front.iter().any(matches) {
+            Side::Front
+        } else if self.back.iter().any(matches) {
+            Side::Back
+        } else if self.tags.as_ref().is_some_and(matches) {
+            Side::Tags
+        } else if self.id.as_ref().is_some_and(matches) {
+            Side::Id
+        } else if self.quantity.as_ref().is_some_and(matches) {
+            Side::Quantity
+        } else {
+            Side::Unused
+        }
+    }
+
+    /// Moves a column to `side`, appending it after the columns already on that side.
+    /// The tags, the ID and the quantity come from one column each, so that column is replaced.
+    pub fn set_side(&mut self, column: ColumnRef, side: Side, headers: Option<&StringRecord>) {
+        let index = column.resolve(headers);
+        self.front.retain(|c| c.resolve(headers) != index);
+        self.back.retain(|c| c.resolve(headers) != index);
+        if self.tags.as_ref().is_some_and(|c| c.resolve(headers) == index) {
+            self.tags = None;
+        }
+        if self.id.as_ref().is_some_and(|c| c.resolve(headers) == index) {
+            self.id = None;
+        }
+        if self.quantity.as_ref().is_some_and(|c| c.resolve(headers) == index) {
+            self.quantity = None;
+        }
+        match side {
+            Side::Front => self.front.push(column),
+            Side::Back => self.back.push(column),
+            Side::Tags => self.tags = Some(column),
+            Side::Id => self.id = Some(column),
+            Side::Quantity => self.quantity = Some(column),
+            Side::Unused => {}
+        }
+    }
+
+    /// Uses the columns headed `tags`, `id` and `quantity` (or `qty` or `copies`)
+    /// for the tags, the ID and the number of copies. If the
+    /// front or the back was on one of them, the front and the back move to the
+    /// first two other columns.
+    pub fn use_named_columns(&mut self, headers: Option<&StringRecord>) {
+        let Some(headers) = headers else {
+            return;
+        };
+        let names = [
+            (&["tags"][..], Side::Tags),
+            (&["id"][..], Side::Id),
+            (&["quantity", "qty", "copies"][..], Side::Quantity),
+        ];
+        for (names, side) in names {
+            if let Some(header) = headers.iter().find(|header| names.iter().any(|name| header.trim().eq_ignore_ascii_case(name))) {
+                self.set_side(ColumnRef::Name(header.to_string()), side, Some(headers));
+            }
+        }
+        if self.front.is_empty() || self.back.is_empty() {
+            let mut others = (0..headers.len())
+                .filter(|&index| !matches!(self.side(index, Some(headers)), Side::Tags | Side::Id | Side::Quantity))
+                .map(|index| ColumnRef::Name(headers[index].to_string()))
+                .collect::<Vec<ColumnRef>>()
+                .into_iter();
+            self.front = others.next().into_iter().collect();
+            self.back = others.next().into_iter().collect();
+        }
+    }
+
+    /// Drops columns that don't exist in `headers`, falling back to the default
+    /// mapping if nothing is left.
+    pub fn retain_resolvable(&mut self, headers: Option<&StringRecord>) {
+        self.front.retain(|c| c.resolve(headers).is_some());
+        self.back.retain(|c| c.resolve(headers).is_some());
+        self.tags = self.tags.take().filter(|c| c.resolve(headers).is_some());
+        self.id = self.id.take().filter(|c| c.resolve(headers).is_some());
+        self.quantity = self.quantity.take().filter(|c| c.resolve(headers).is_some());
+        if self.front.is_empty() && self.back.is_empty() {
+            *self = ColumnMapping {
+                separator: self.separator.clone(),
+                ..Default::default()
+            };
+        }
+    }
+
+    pub fn resolve(&self, headers: Option<&StringRecord>) -> Result<ResolvedMapping, Box<dyn std::error::Error>> {
+        let resolve_side = |columns: &Vec<ColumnRef>| -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+            columns.iter()
+                .map(|column| column.resolve(headers).ok_or_else(|| match column {
+                    ColumnRef::Name(name) => format!("No column named \"{}\"", name).into(),
+                    ColumnRef::Index(index) => format!("No column {}", index).into(),
+                }))
+                .collect()
+        };
+        Ok(ResolvedMapping {
+            front: resolve_side(&self.front)?,
+            back: resolve_side(&self.back)?,
+            tags: resolve_side(&self.tags.iter().cloned().collect())?.first().copied(),
+            id: resolve_side(&self.id.iter().cloned().collect())?.first().copied(),
+            quantity: resolve_side(&self.quantity.iter().cloned().collect())?.first().copied(),
+            separator: self.separator.clone(),
+        })
+    }
+}
+
+/// A `ColumnMapping` with every column turned into a position, ready to apply to records.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResolvedMapping {
+    pub front: Vec<usize>,
+    pub back: Vec<usize>,
+    pub tags: Option<usize>,
+    pub id: Option<usize>,
+    pub quantity: Option<usize>,
+    pub separator: String,
+}
+
+impl ResolvedMapping {
+    /// The front text of a record, or `None` if the record has none of the front columns.
+    pub fn front(&self, record: &StringRecord) -> Option<String> {
+        self.join(&self.front, record)
+    }
+
+    /// The back text of a record, or `None` if the record has none of the back columns.
+    pub fn back(&self, record: &StringRecord) -> Option<String> {
+        self.join(&self.back, record)
+    }
+
+    /// The tags in the record's tags column.
+    pub fn tags(&self, record: &StringRecord) -> Vec<String> {
+        self.tags.and_then(|index| record.get(index)).map(split_tags).unwrap_or_default()
+    }
+
+    /// The record's ID, or an empty string if there is no ID column.
+    pub fn id(&self, record: &StringRecord) -> String {
+        self.id.and_then(|index| record.get(index)).unwrap_or_default().trim().to_string()
+    }
+
+    /// The number of copies in the record's quantity column, `None` if it is
+    /// missing or blank, or an error if it isn't a whole number.
+    pub fn quantity(&self, record: &StringRecord) -> Result<Option<usize>, String> {
+        let Some(text) = self.quantity.and_then(|index| record.get(index)).map(str::trim).filter(|text| !text.is_empty()) else {
+            return Ok(None);
+        };
+        // Spreadsheets give whole numbers as 3.0
+        let text = text.strip_suffix(".0").unwrap_or(text);
+        text.parse().map(Some).map_err(|_| format!("quantity \"{}\" is not a whole number, printed once", text))
+    }
+
+    fn join(&self, columns: &[usize], record: &StringRecord) -> Option<String> {
+        let fields: Vec<&str> = columns.iter().filter_map(|&index| record.get(index)).collect();
+        if fields.is_empty() {
+            return None;
+        }
+        Some(fields.into_iter()
+            .filter(|field| !field.trim().is_empty())
+            .collect::<Vec<&str>>()
+            .join(&self.separator))
+    }
+}
+
+/// Turns rows into cards. Each row is checked against the width of the header
+/// (or of the first row if there is no header), and rows that don't match are
+/// handled according to `policy`. If `directives` is set, rows such as
+/// `#pagebreak` control the layout instead of becoming cards.
+pub fn cards_from_records<I>(headers: Option<&StringRecord>, records: I, mapping: &ColumnMapping, policy: RowPolicy, directives: bool) -> Result<Deck, Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = Result<(usize, StringRecord), Box<dyn std::error::Error>>>,
+{
+    let columns = mapping.resolve(headers)?;
+    let mut expected_len = headers.map(|h| h.len());
+    let mut deck = Deck::default();
+    let mut section: Option<String> = None;
+    let mut page_break = false;
+
+    for result in records {
+        let (row, record) = result?;
+        if let Some(directive) = Directive::parse(&record).filter(|_| directives) {
+            match directive {
+                Directive::Section(name) => {
+                    page_break = true;
+                    if !name.is_empty() {
+                        deck.cards.push(Card {
+                            front: name.clone(),
+                            back: name.clone(),
+                            row,
+                            tags: vec![name.clone()],
+                            page_break: std::mem::take(&mut page_break),
+                            ..Card::default()
+                        });
+                    }
+                    section = Some(name).filter(|name| !name.is_empty());
+                }
+                Directive::PageBreak => page_break = true,
+                Directive::Comment => {}
+            }
+            continue;
+        }
+        let expected = *expected_len.get_or_insert(record.len());
+
+        let front = columns.front(&record);
+        let back = columns.back(&record);
+        let missing = record.len() < expected || front.is_none() || back.is_none();
+        if missing || record.len() > expected {
+            let message = format!("expected {} fields but found {}", expected, record.len());
+            match policy {
+                RowPolicy::Abort => return Err(Diagnostic::new(row, message).to_string().into()),
+                RowPolicy::Skip => {
+                    deck.diagnostics.push(Diagnostic::new(row, format!("{}, row skipped", message)));
+                    continue;
+                }
+                RowPolicy::Blank if missing => {
+                    deck.diagnostics.push(Diagnostic::new(row, format!("{}, missing fields left blank", message)));
+                }
+                RowPolicy::Blank => {
+                    deck.diagnostics.push(Diagnostic::new(row, format!("{}, extra fields ignored", message)));
+                }
+            }
+        }
+
+        let quantity = columns.quantity(&record).unwrap_or_else(|message| {
+            deck.diagnostics.push(Diagnostic::new(row, message));
+            None
+        });
+        let quantity = quantity.map(|quantity| check_quantity(quantity).unwrap_or_else(|message| {
+            deck.diagnostics.push(Diagnostic::new(row, message));
+            MAX_QUANTITY
+        }));
+        deck.cards.push(Card {
+            front: front.unwrap_or_default(),
+            back: back.unwrap_or_default(),
+            row,
+            quantity,
+            id: columns.id(&record),
+            tags: section.iter().cloned().chain(columns.tags(&record)).collect(),
+            page_break: std::mem::take(&mut page_break),
+            ..Card::default()
+        });
+    }
+
+    Ok(deck)
+}
+
+/// Splits a tags field on commas or semicolons if it has any, and on spaces
+/// otherwise, like Anki does.
+pub fn split_tags(text: &str) -> Vec<String> {
+    let tags: Vec<&str> = if text.contains([',', ';']) {
+        text.split([',', ';']).collect()
+    } else {
+        text.split_whitespace().collect()
+    };
+    tags.into_iter()
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// A short ID derived from the text of a card, the same in every print run.
+/// It is the 64-bit FNV-1a hash of both sides folded to eight hex digits.
+pub fn content_id(front: &str, back: &str) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in front.bytes().chain([0x1f]).chain(back.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:08x}", (hash >> 32) ^ (hash & 0xffff_ffff))
+}
+
+/// Gives every card without an ID one derived from its text. Cards with the
+/// same text are told apart by numbering the later ones `-2`, `-3`, and so on.
+pub fn assign_ids(cards: &mut [Card]) {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for card in cards.iter_mut().filter(|card| card.id.is_empty()) {
+        let id = content_id(&card.front, &card.back);
+        let count = seen.entry(id.clone()).or_default();
+        *count += 1;
+        card.id = if *count == 1 { id } else { format!("{}-{}", id, count) };
+    }
+}
+
+/// The most copies of one card that are printed, so that a mistyped quantity
+/// doesn't fill memory with copies.
+pub const MAX_QUANTITY: usize = 1000;
+
+/// Checks a quantity against `MAX_QUANTITY`, with the message to report if it is over.
+pub fn check_quantity(quantity: usize) -> Result<usize, String> {
+    if quantity > MAX_QUANTITY {
+        return Err(format!("quantity {} is more than {}, printed {} times", quantity, MAX_QUANTITY, MAX_QUANTITY));
+    }
+    Ok(quantity)
+}
+
+/// Where the copies of a card with a quantity go.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CopyPlacement {
+    /// All copies of a card one after the other.
+    Together,
+    /// One copy of every card, then the second copies on a new sheet, and so on,
+    /// so the copies end up on different sheets. Cards don't move past a page break.
+    Spread,
+}
+
+/// Repeats each card as many times as its quantity asks for.
+pub fn expand_copies(cards: &[Card], placement: CopyPlacement) -> Vec<Card> {
+    let quantity = |card: &Card| card.quantity.unwrap_or(1);
+    let mut expanded = Vec::new();
+    match placement {
+        CopyPlacement::Together => {
+            // A page break on a card left out with a quantity of 0 moves to the next card
+            let mut page_break = false;
+            for card in cards {
+                page_break |= card.page_break;
+                for _ in 0..quantity(card) {
+                    expanded.push(Card { page_break: std::mem::take(&mut page_break), ..card.clone() });
+                }
+            }
+        }
+        CopyPlacement::Spread => {
+            for group in cards.chunk_by(|_, next| !next.page_break) {
+                let start = expanded.len();
+                let rounds = group.iter().map(quantity).max().unwrap_or(0);
+                for copy in 0..rounds {
+                    let round = expanded.len();
+                    expanded.extend(group.iter().filter(|card| quantity(card) > copy).map(|card| Card { page_break: false, ..card.clone() }));
+                    if let Some(first) = expanded.get_mut(round) {
+                        first.page_break = copy > 0;
+                    }
+                }
+                if let Some(first) = expanded.get_mut(start) {
+                    first.page_break = group[0].page_break;
+                }
+            }
+        }
+    }
+    expanded
+}
+
+/// Which cards of the print run are printed, for example to reprint lost cards.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CardFilter {
+    /// Only the cards with this tag, unless it is empty.
+    pub tag: String,
+    /// Only the cards with these IDs, separated by commas or spaces, unless it is empty.
+    pub ids: String,
+}
+
+impl CardFilter {
+    pub fn matches(&self, card: &Card) -> bool {
+        let tag = self.tag.trim();
+        let mut ids = self.ids.split([',', ' ']).map(str::trim).filter(|id| !id.is_empty()).peekable();
+        (tag.is_empty() || card.tags.iter().any(|t| t.trim().eq_ignore_ascii_case(tag)))
+            && (ids.peek().is_none() || ids.any(|id| id.eq_ignore_ascii_case(&card.id)))
+    }
+}
+
+/// Loads a deck from the decoded text of a CSV file.
+pub fn load_csv(text: &str, has_headers: bool, dialect: &Dialect, mapping: &ColumnMapping, policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+    let text = dialect.strip_comments(text);
+    let mut reader = dialect.reader_builder(has_headers).from_reader(text.as_bytes());
+
+    let headers = if has_headers { Some(reader.headers()?.clone()) } else { None };
+    let mut lines = LineCounter { text: text.as_bytes(), byte: 0, line: 1 };
+    let records = reader.into_records().map(|result| {
+        let record = result?;
+        let row = record.position().map_or(0, |position| lines.line_at(position.byte() as usize));
+        Ok((row, record))
+    });
+    cards_from_records(headers.as_ref(), records, mapping, policy, true)
+}
+
+/// Works out which line a record starts on. The csv reader's own line numbers
+/// leave out the blank lines it skips before a record.
+struct LineCounter<'a> {
+    text: &'a [u8],
+    byte: usize,
+    line: usize,
+}
+
+impl LineCounter<'_> {
+    fn line_at(&mut self, byte: usize) -> usize {
+        let mut start = byte.max(self.byte);
+        while start < self.text.len() && (self.text[start] == b'\n' || self.text[start] == b'\r') {
+            start += 1;
+        }
+        self.line += self.text[self.byte..start].iter().filter(|&&b| b == b'\n').count();
+        self.byte = start;
+        self.line
+    }
+}
+
+/// Reads the first row of a CSV file so its columns can be listed.
+pub fn read_first_row(text: &str, dialect: &Dialect) -> Result<StringRecord, Box<dyn std::error::Error>> {
+    let text = dialect.strip_comments(text);
+    let mut reader = dialect.reader_builder(false).from_reader(text.as_bytes());
+    match reader.records().next() {
+        Some(record) => Ok(record?),
+        None => Err("The CSV file is empty".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_rows(rows: Vec<Vec<&str>>, policy: RowPolicy, directives: bool) -> Result<Deck, Box<dyn std::error::Error>> {
+        let headers = StringRecord::from(vec!["Front", "Back"]);
+        let records = rows.into_iter().enumerate().map(|(index, fields)| Ok((index + 2, StringRecord::from(fields))));
+        cards_from_records(Some(&headers), records, &ColumnMapping::default(), policy, directives)
+    }
+
+    /// Loads a header and three rows: one that fits, one short and one long.
+    fn load(policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+        load_rows(vec![vec!["a", "1"], vec!["b"], vec!["c", "3", "x"]], policy, false)
+    }
+
+    fn sides(deck: &Deck) -> Vec<(&str, &str)> {
+        deck.cards.iter().map(|card| (card.front.as_str(), card.back.as_str())).collect()
+    }
+
+    fn messages(deck: &Deck) -> Vec<String> {
+        deck.diagnostics.iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn skip_leaves_out_bad_rows() {
+        let deck = load(RowPolicy::Skip).unwrap();
+        assert_eq!(sides(&deck), [("a", "1")]);
+        assert_eq!(messages(&deck), [
+            "Row 3: expected 2 fields but found 1, row skipped",
+            "Row 4: expected 2 fields but found 3, row skipped",
+        ]);
+    }
+
+    #[test]
+    fn blank_keeps_bad_rows() {
+        let deck = load(RowPolicy::Blank).unwrap();
+        assert_eq!(sides(&deck), [("a", "1"), ("b", ""), ("c", "3")]);
+        assert_eq!(messages(&deck), [
+            "Row 3: expected 2 fields but found 1, missing fields left blank",
+            "Row 4: expected 2 fields but found 3, extra fields ignored",
+        ]);
+    }
+
+    #[test]
+    fn abort_stops_at_the_first_bad_row() {
+        let error = load(RowPolicy::Abort).unwrap_err();
+        assert_eq!(error.to_string(), "Row 3: expected 2 fields but found 1");
+    }
+
+    #[test]
+    fn directive_rows_lay_out_the_cards() {
+        let rows = vec![vec!["a", "1"], vec!["#pagebreak"], vec!["b", "2"], vec!["#comment", "x"], vec!["#section", "Animals"], vec!["c", "3"]];
+        let deck = load_rows(rows, RowPolicy::Abort, true).unwrap();
+        assert_eq!(sides(&deck), [("a", "1"), ("b", "2"), ("Animals", "Animals"), ("c", "3")]);
+        let page_breaks: Vec<bool> = deck.cards.iter().map(|card| card.page_break).collect();
+        assert_eq!(page_breaks, [false, true, true, false]);
+        assert_eq!(deck.cards[3].tags, ["Animals"]);
+    }
+
+    #[test]
+    fn quantity_column_sets_the_copies() {
+        let headers = StringRecord::from(vec!["Front", "Back", "Qty"]);
+        let rows = [vec!["a", "1", "3"], vec!["b", "2", "2.0"], vec!["c", "3", ""], vec!["d", "4", "lots"], vec!["e", "5", "5000"]];
+        let records = rows.into_iter().enumerate().map(|(index, fields)| Ok((index + 2, StringRecord::from(fields))));
+        let mapping = ColumnMapping { quantity: Some(ColumnRef::Index(2)), ..ColumnMapping::default() };
+        let deck = cards_from_records(Some(&headers), records, &mapping, RowPolicy::Abort, false).unwrap();
+        let quantities: Vec<Option<usize>> = deck.cards.iter().map(|card| card.quantity).collect();
+        assert_eq!(quantities, [Some(3), Some(2), None, None, Some(MAX_QUANTITY)]);
+        assert_eq!(messages(&deck), [
+            "Row 5: quantity \"lots\" is not a whole number, printed once",
+            "Row 6: quantity 5000 is more than 1000, printed 1000 times",
+        ]);
+    }
+
+    fn card(front: &str, quantity: usize) -> Card {
+        Card { front: front.to_string(), quantity: Some(quantity), ..Card::default() }
+    }
+
+    fn fronts(cards: &[Card]) -> String {
+        cards.iter().map(|card| card.front.as_str()).collect()
+    }
+
+    #[test]
+    fn expand_copies_together() {
+        let cards = [card("a", 2), card("b", 0), card("c", 3)];
+        assert_eq!(fronts(&expand_copies(&cards, CopyPlacement::Together)), "aaccc");
+    }
+
+    #[test]
+    fn expand_copies_keeps_page_breaks_of_left_out_cards() {
+        let cards = [card("a", 1), Card { page_break: true, ..card("b", 0) }, card("c", 1)];
+        let expanded = expand_copies(&cards, CopyPlacement::Together);
+        assert_eq!(fronts(&expanded), "ac");
+        assert!(expanded[1].page_break);
+    }
+
+    #[test]
+    fn expand_copies_spread_within_page_breaks() {
+        let cards = [card("a", 2), card("b", 1), Card { page_break: true, ..card("c", 2) }, card("d", 1)];
+        let expanded = expand_copies(&cards, CopyPlacement::Spread);
+        assert_eq!(fronts(&expanded), "abacdc");
+        let page_breaks: Vec<bool> = expanded.iter().map(|card| card.page_break).collect();
+        assert_eq!(page_breaks, [false, false, true, true, false, true]);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::directive;
+use csv::ReaderBuilder;
+
+/// The delimiters Facade tries when sniffing a file, in order of preference.
+/// Commas come after the rarer delimiters as they also turn up inside the text.
+pub const DELIMITERS: [(u8, &str); 5] = [
+    (b'\t', "Tab"),
+    (b';', "Semicolon"),
+    (b'|', "Pipe"),
+    (b',', "Comma"),
+    (b':', "Colon"),
+];
+
+/// How the fields of a CSV file are separated and quoted.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dialect {
+    pub delimiter: u8,
+    pub quote: u8,
+    /// The character that escapes a quote inside a quoted field,
+    /// or `None` if quotes are escaped by doubling them.
+    pub escape: Option<u8>,
+    /// Lines starting with this prefix are skipped. Empty means no comments.
+    pub comment_prefix: String,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: b',',
+            quote: b'"',
+            escape: None,
+            comment_prefix: String::new(),
+        }
+    }
+}
+
+impl Dialect {
+    pub fn reader_builder(&self, has_headers: bool) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder
+            .has_headers(has_headers)
+            .flexible(true)
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .escape(self.escape)
+            .double_quote(self.escape.is_none());
+        builder
+    }
+
+    /// Blanks out comment lines. The lines are kept (empty) so the csv reader
+    /// still reports the right line numbers, and lines inside quoted fields and
+    /// directives such as `#pagebreak` are left alone.
+    pub fn strip_comments(&self, text: &str) -> String {
+        if self.comment_prefix.is_empty() {
+            return text.to_string();
+        }
+        let mut output = String::with_capacity(text.len());
+        let mut in_quotes = false;
+        for line in text.split_inclusive('\n') {
+            if !in_quotes && line.trim_start().starts_with(&self.comment_prefix) && !directive::is_directive_line(line) {
+                output.push('\n');
+                continue;
+            }
+            in_quotes = self.ends_in_quotes(line, in_quotes);
+            output.push_str(line);
+        }
+        output
+    }
+
+    fn ends_in_quotes(&self, line: &str, mut in_quotes: bool) -> bool {
+        let mut bytes = line.bytes();
+        while let Some(b) = bytes.next() {
+            if in_quotes && self.escape == Some(b) {
+                bytes.next();
+            } else if b == self.quote {
+                in_quotes = !in_quotes;
+            }
+        }
+        in_quotes
+    }
+}
+
+/// Guesses the dialect of a CSV file from a sample of its text.
+/// The comment prefix can't be guessed and is left empty.
+pub fn sniff(sample: &str) -> Dialect {
+    let sample = match sample.char_indices().nth(64 * 1024) {
+        Some((end, _)) => &sample[..end],
+        None => sample,
+    };
+
+    let quote = sniff_quote(sample);
+    let escape = if sample.contains(&format!("\\{}", quote as char)) && !sample.contains(&format!("{0}{0}", quote as char)) {
+        Some(b'\\')
+    } else {
+        None
+    };
+
+    // Pick the delimiter that splits the most records into the same number of fields.
+    // On a tie the delimiter listed first in DELIMITERS wins
+    let mut best = (0, 0, b',');
+    for (delimiter, _) in DELIMITERS {
+        let counts = field_counts(sample, delimiter, quote);
+        let Some(&widest) = counts.iter().max() else {
+            continue;
+        };
+        if widest < 2 {
+            continue;
+        }
+        let mut most_common = (0, 0);
+        for &count in counts.iter().filter(|&&c| c > 1) {
+            let frequency = counts.iter().filter(|&&c| c == count).count();
+            if frequency > most_common.0 || (frequency == most_common.0 && count > most_common.1) {
+                most_common = (frequency, count);
+            }
+        }
+        if most_common > (best.0, best.1) {
+            best = (most_common.0, most_common.1, delimiter);
+        }
+    }
+
+    Dialect {
+        delimiter: best.2,
+        quote,
+        escape,
+        comment_prefix: String::new(),
+    }
+}
+
+fn sniff_quote(sample: &str) -> u8 {
+    // A quote character opens a field right after a line break or a delimiter
+    let opens_field = |quote: char| {
+        let mut previous = '\n';
+        let mut count = 0;
+        for c in sample.chars() {
+            if c == quote && (previous == '\n' || DELIMITERS.iter().any(|&(d, _)| d as char == previous)) {
+                count += 1;
+            }
+            previous = c;
+        }
+        count
+    };
+    if opens_field('\'') > opens_field('"') {
+        b'\''
+    } else {
+        b'"'
+    }
+}
+
+/// The number of fields in each record of the sample, skipping blank lines.
+fn field_counts(sample: &str, delimiter: u8, quote: u8) -> Vec<usize> {
+    let mut counts = Vec::new();
+    let mut fields = 1;
+    let mut in_quotes = false;
+    let mut blank = true;
+    for b in sample.bytes() {
+        if b == quote {
+            in_quotes = !in_quotes;
+        } else if !in_quotes && b == delimiter {
+            fields += 1;
+        } else if !in_quotes && b == b'\n' {
+            if !blank {
+                counts.push(fields);
+            }
+            fields = 1;
+            blank = true;
+            continue;
+        }
+        if !b.is_ascii_whitespace() {
+            blank = false;
+        }
+    }
+    if !blank {
+        counts.push(fields);
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_finds_the_delimiter() {
+        assert_eq!(sniff("front,back\na,b\nc,d\n").delimiter, b',');
+        assert_eq!(sniff("front;back\na;b, c\nd;e\n").delimiter, b';');
+        assert_eq!(sniff("front\tback\na, b\tc\nd\te\n").delimiter, b'\t');
+        assert_eq!(sniff("a|b|c\nd|e|f\n").delimiter, b'|');
+    }
+
+    #[test]
+    fn sniff_prefers_other_delimiters_to_commas_on_a_tie() {
+        assert_eq!(sniff("Hund\tdog, a pet\nKatze\tcat, a pet\n").delimiter, b'\t');
+        assert_eq!(sniff("Hund;dog, a pet\n").delimiter, b';');
+        assert_eq!(sniff("Hund|dog, a pet\n").delimiter, b'|');
+    }
+
+    #[test]
+    fn sniff_ignores_delimiters_in_quotes() {
+        assert_eq!(sniff("\"a;b\",c\n\"d;e\",f\n").delimiter, b',');
+    }
+
+    #[test]
+    fn sniff_finds_the_quote_and_escape() {
+        assert_eq!(sniff("'a,b',c\n'd',e\n").quote, b'\'');
+        assert_eq!(sniff("\"a \"\"b\"\"\",c\n").escape, None);
+        assert_eq!(sniff("\"a \\\"b\\\" c\",d\n").escape, Some(b'\\'));
+    }
+
+    #[test]
+    fn strip_comments_keeps_line_numbers() {
+        let dialect = Dialect { comment_prefix: "#".to_string(), ..Dialect::default() };
+        assert_eq!(dialect.strip_comments("# note\na,b\n\"c\n# not a comment\",d\n"), "\na,b\n\"c\n# not a comment\",d\n");
+    }
+}
@@ -0,0 +1,45 @@
+use csv::StringRecord;
+
+/// A row of a deck that controls the layout instead of being printed as a card.
+/// Its first field is the directive's name, such as `#pagebreak`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Directive {
+    /// `#section,Name` starts a new sheet with a header card showing the name,
+    /// and tags the cards that follow with it.
+    Section(String),
+    /// `#pagebreak` puts the next card on a fresh sheet.
+    PageBreak,
+    /// `#comment,...` is left out of the deck.
+    Comment,
+}
+
+const NAMES: [&str; 3] = ["section", "pagebreak", "comment"];
+
+impl Directive {
+    pub fn parse(record: &StringRecord) -> Option<Directive> {
+        let name = record.get(0)?.trim().strip_prefix('#')?.to_ascii_lowercase();
+        let argument = record.iter()
+            .skip(1)
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        match name.as_str() {
+            "section" => Some(Directive::Section(argument)),
+            "pagebreak" => Some(Directive::PageBreak),
+            "comment" => Some(Directive::Comment),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a line of text starts with a directive. Directives take precedence
+/// over a comment prefix, so `#section` still works when `#` starts comments.
+pub fn is_directive_line(line: &str) -> bool {
+    let Some(rest) = line.trim_start().strip_prefix('#') else {
+        return false;
+    };
+    let name: String = rest.chars().take_while(char::is_ascii_alphabetic).collect();
+    let after = rest[name.len()..].chars().next();
+    NAMES.contains(&name.to_ascii_lowercase().as_str()) && !after.is_some_and(char::is_alphanumeric)
+}
@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use svg2pdf::usvg::fontdb::{self, Database, Family, Query};
+
+/// Millimetres in a point.
+pub const MM_PER_PT: f64 = 25.4 / 72.0;
+
+/// Width of a glyph in ems when no font could be found at all.
+const FALLBACK_ADVANCE: f64 = 0.55;
+
+/// The size of superscripts and subscripts, relative to the text around them.
+pub const SMALL_SCALE: f64 = 0.7;
+
+/// Whether text is set in bold or italic and how large, relative to the text
+/// around it, which changes how wide it is.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct TextStyle {
+    pub bold: bool,
+    pub italic: bool,
+    pub size_percent: u16,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle { bold: false, italic: false, size_percent: 100 }
+    }
+}
+
+/// The system fonts, with the metrics of every family used so far.
+pub struct Fonts {
+    database: Database,
+    families: HashMap<String, FontMetrics>,
+}
+
+impl Fonts {
+    pub fn load() -> Self {
+        let mut database = Database::new();
+        database.load_system_fonts();
+        Fonts { database, families: HashMap::new() }
+    }
+
+    /// The metrics of `family`. A family that isn't installed is measured with
+    /// the font the SVG renderer falls back to.
+    pub fn metrics(&mut self, family: &str) -> &FontMetrics {
+        self.families.entry(family.to_string()).or_insert_with(|| FontMetrics::new(&self.database, family))
+    }
+}
+
+/// A face of a font family, copied out of the font database.
+struct Face {
+    data: Vec<u8>,
+    index: u32,
+}
+
+/// Measures text set in one font family, so it can be wrapped to the width of a card.
+pub struct FontMetrics {
+    faces: HashMap<TextStyle, Face>,
+    /// Advances in ems, looked up once per character.
+    advances: RefCell<HashMap<(TextStyle, char), f64>>,
+}
+
+impl FontMetrics {
+    fn new(database: &Database, family: &str) -> Self {
+        let mut faces = HashMap::new();
+        for bold in [false, true] {
+            for italic in [false, true] {
+                let query = Query {
+                    families: &[Family::Name(family), Family::SansSerif],
+                    weight: if bold { fontdb::Weight::BOLD } else { fontdb::Weight::NORMAL },
+                    style: if italic { fontdb::Style::Italic } else { fontdb::Style::Normal },
+                    ..Query::default()
+                };
+                let id = database.query(&query).or_else(|| database.faces().next().map(|face| face.id));
+                let face = id.and_then(|id| database.with_face_data(id, |data, index| Face { data: data.to_vec(), index }));
+                if let Some(face) = face {
+                    faces.insert(TextStyle { bold, italic, ..TextStyle::default() }, face);
+                }
+            }
+        }
+        FontMetrics { faces, advances: RefCell::new(HashMap::new()) }
+    }
+
+    /// The width of `text` in millimetres when set at `size` points.
+    pub fn width(&self, text: &str, size: f64, style: TextStyle) -> f64 {
+        // Larger and smaller text is set in the same face
+        let size = size * style.size_percent as f64 / 100.0;
+        let style = TextStyle { size_percent: 100, ..style };
+        let mut advances = self.advances.borrow_mut();
+        let mut face = None;
+        let ems: f64 = text.chars()
+            .map(|c| *advances.entry((style, c)).or_insert_with(|| {
+                let face = face.get_or_insert_with(|| self.faces.get(&style)
+                    .and_then(|face| ttf_parser::Face::parse(&face.data, face.index).ok()));
+                face.as_ref().map_or(FALLBACK_ADVANCE, |face| advance(face, c))
+            }))
+            .sum();
+        ems * size * MM_PER_PT
+    }
+}
+
+/// The advance of a character in ems, using the missing-glyph box for characters the face lacks.
+fn advance(face: &ttf_parser::Face, c: char) -> f64 {
+    let glyph = face.glyph_index(c).unwrap_or(ttf_parser::GlyphId(0));
+    match face.glyph_hor_advance(glyph) {
+        Some(advance) => advance as f64 / face.units_per_em() as f64,
+        None => FALLBACK_ADVANCE,
+    }
+}
@@ -0,0 +1,50 @@
+/// The languages words can be hyphenated in, by ISO 639-1 code. Their TeX
+/// hyphenation patterns are built into Facade.
+pub const LANGUAGES: [(&str, &str); 35] = [
+    ("af", "Afrikaans"),
+    ("sq", "Albanian"),
+    ("be", "Belarusian"),
+    ("bg", "Bulgarian"),
+    ("ca", "Catalan"),
+    ("hr", "Croatian"),
+    ("cs", "Czech"),
+    ("da", "Danish"),
+    ("nl", "Dutch"),
+    ("en", "English"),
+    ("et", "Estonian"),
+    ("fi", "Finnish"),
+    ("fr", "French"),
+    ("ka", "Georgian"),
+    ("de", "German"),
+    ("el", "Greek"),
+    ("hu", "Hungarian"),
+    ("is", "Icelandic"),
+    ("it", "Italian"),
+    ("ku", "Kurmanji"),
+    ("la", "Latin"),
+    ("lt", "Lithuanian"),
+    ("mn", "Mongolian"),
+    ("no", "Norwegian"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("sr", "Serbian"),
+    ("sk", "Slovak"),
+    ("sl", "Slovenian"),
+    ("es", "Spanish"),
+    ("sv", "Swedish"),
+    ("tr", "Turkish"),
+    ("tk", "Turkmen"),
+    ("uk", "Ukrainian"),
+];
+
+/// The hyphenation patterns for an ISO 639-1 code such as `de`, if there are any.
+pub fn language(code: &str) -> Option<hypher::Lang> {
+    let code: [u8; 2] = code.trim().to_ascii_lowercase().as_bytes().try_into().ok()?;
+    hypher::Lang::from_iso(code)
+}
+
+/// The name of the language with the code, for showing in the GUI.
+pub fn language_name(code: &str) -> Option<&'static str> {
+    LANGUAGES.iter().find(|(iso, _)| iso.eq_ignore_ascii_case(code.trim())).map(|(_, name)| *name)
+}
@@ -1,9 +1,22 @@
 #![windows_subsystem = "windows"]
+mod anki;
+mod charset;
+mod cli;
+mod deck;
+mod dialect;
+mod directive;
+mod entities;
+mod fonts;
+mod hyphenation;
+mod markdown;
+mod notes;
+mod plaintext;
+mod spreadsheet;
+mod structured;
 mod textutils;
 
 use eframe::{egui};
 use eframe::egui::{IconData, ViewportBuilder};
-use egui_extras;
 use egui_file_dialog::FileDialog;
 
 use resvg::tiny_skia::{Pixmap, Transform};
@@ -14,16 +27,35 @@ use std::io::{Error, Read, Write};
 use tempdir::TempDir;
 use std::path::Path;
 use std::path::PathBuf;
-use csv::ReaderBuilder;
+use csv::StringRecord;
+use deck::{Card, CardFilter, ColumnMapping, ColumnRef, CopyPlacement, Deck, Diagnostic, OverflowPolicy, RowPolicy, Side};
+use dialect::Dialect;
+use fonts::{FontMetrics, Fonts};
+use encoding_rs::Encoding;
+use plaintext::{CardSeparator, PlainTextFormat};
+use textutils::Markup;
 use lopdf::{Document, Object};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use svg2pdf::{self, PageOptions, ConversionOptions};
 
+#[allow(clippy::upper_case_acronyms)]
 struct FCDS<'a> {
     save_file_dialog: FileDialog,
     open_file_dialog: FileDialog,
-    csv_file: Option<PathBuf>,
+    /// The deck files of the print run, in the order they are printed.
+    decks: Vec<DeckSource>,
+    /// The deck whose settings are shown.
+    selected_deck: usize,
+    divider_cards: bool,
+    row_policy: RowPolicy,
+    overflow: OverflowPolicy,
+    filter: CardFilter,
+    print_ids: bool,
+    copies: CopyPlacement,
+    fit_font_size: bool,
+    min_font_size: f64,
+    max_font_size: f64,
     page_width: f64,
     page_height: f64,
     num_cards_width: i32,
@@ -32,58 +64,845 @@ struct FCDS<'a> {
     flip_vertical: bool,
     preview_page: i32,
     total_pages: i32,
-    error: bool,
+    error: Option<String>,
+    warnings: Vec<String>,
     generated: bool,
-    header: bool,
     saved: bool,
     tmp_path: &'a Path,
 }
 
+/// Where the text of a deck comes from.
+enum DeckOrigin {
+    File(PathBuf),
+    /// Text pasted into the GUI or read from stdin, read like a CSV file.
+    Pasted(String),
+}
+
+/// One deck of the print run and the settings it is read with.
+struct DeckSource {
+    origin: DeckOrigin,
+    /// Shown on the divider card in front of the deck.
+    title: String,
+    csv_first_row: Option<StringRecord>,
+    header: bool,
+    dialect: Dialect,
+    encoding: Option<&'static Encoding>,
+    detected_encoding: Option<&'static Encoding>,
+    read_as_plain_text: bool,
+    plain_text_format: PlainTextFormat,
+    sheet_names: Vec<String>,
+    sheet: String,
+    first_row: usize,
+    note_types: Vec<anki::NoteType>,
+    note_type: String,
+    note_type_mappings: HashMap<String, ColumnMapping>,
+    column_mapping: ColumnMapping,
+    /// The ISO 639-1 code of the language long words are hyphenated in, or empty
+    /// to split them anywhere. Cards can choose their own.
+    language: String,
+    /// How bold, italic and the like are written in the text of the cards.
+    markup: Markup,
+    /// How pasted text parses, or why it doesn't.
+    preview: Option<Result<Deck, String>>,
+}
+
+/// The standard paper sizes offered in the GUI and on the command line, in millimetres.
+const PAPER_SIZES: [(&str, f64, f64); 4] = [
+    ("A4", 210.0, 297.0),
+    ("Letter", 215.9, 279.4),
+    ("Legal", 215.9, 355.6),
+    ("Tabloid", 279.0, 432.0),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct PageLayout {
+    page_width: f64,
+    page_height: f64,
+    num_cards_width: i32,
+    num_cards_height: i32,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    /// Print each card's ID small in its corner.
+    print_ids: bool,
+    /// Where the copies of cards with a quantity go.
+    copies: CopyPlacement,
+    /// The smallest and largest font size in points, to fit the text of each side
+    /// to the card. Sizes set by the card itself take precedence.
+    fit_font_size: Option<(f64, f64)>,
+    /// What to do with cards whose text doesn't fit.
+    overflow: OverflowPolicy,
+}
+
+impl PageLayout {
+    /// The width and height in millimetres the text on a card may take up.
+    fn text_area(&self) -> (f64, f64) {
+        (TEXT_SIZE * self.page_width / self.num_cards_width as f64, TEXT_SIZE * self.page_height / self.num_cards_height as f64)
+    }
+
+    /// The font size of one side of a card: the card's own size, the size that
+    /// fits its text if fitting is turned on, or else the default for the side.
+    fn font_size(&self, card: &Card, back: bool, metrics: &FontMetrics) -> f64 {
+        let (text, card_size, default_size) = if back {
+            (&card.back, card.style.back_size, 12.0)
+        } else {
+            (&card.front, card.style.front_size, 30.0)
+        };
+        let (max_width, max_height) = self.text_area();
+        match (card_size, self.fit_font_size) {
+            (Some(size), _) => size,
+            (None, Some(sizes)) => textutils::fit_font_size(text, max_width, max_height, sizes, LINE_HEIGHT, metrics),
+            (None, None) => default_size,
+        }
+    }
+}
+
+impl DeckSource {
+    fn open(origin: DeckOrigin, tmp_path: &Path) -> Self {
+        let title = match &origin {
+            DeckOrigin::File(path) => path.file_stem().unwrap_or(path.as_os_str()).to_string_lossy().into_owned(),
+            DeckOrigin::Pasted(_) => "Pasted deck".to_string(),
+        };
+        let mut source = DeckSource {
+            origin,
+            title,
+            csv_first_row: None,
+            header: true,
+            dialect: Dialect::default(),
+            encoding: None,
+            detected_encoding: None,
+            read_as_plain_text: false,
+            plain_text_format: PlainTextFormat::default(),
+            sheet_names: Vec::new(),
+            sheet: String::new(),
+            first_row: 1,
+            note_types: Vec::new(),
+            note_type: String::new(),
+            note_type_mappings: HashMap::new(),
+            column_mapping: ColumnMapping::default(),
+            language: String::new(),
+            markup: Markup::default(),
+            preview: None,
+        };
+        source.detect_format(tmp_path);
+        source
+    }
+
+    /// The file name, used to tell the decks of the print run apart in messages.
+    fn name(&self) -> String {
+        match &self.origin {
+            DeckOrigin::File(path) => path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned(),
+            DeckOrigin::Pasted(_) => self.title.clone(),
+        }
+    }
+
+    fn file(&self) -> Option<&Path> {
+        match &self.origin {
+            DeckOrigin::File(path) => Some(path),
+            DeckOrigin::Pasted(_) => None,
+        }
+    }
+
+    /// Whether the deck is delimited or plain text, as opposed to a spreadsheet, a package or notes.
+    fn is_text(&self) -> bool {
+        match &self.origin {
+            DeckOrigin::File(path) => is_csv(path),
+            DeckOrigin::Pasted(_) => true,
+        }
+    }
+
+    fn csv_headers(&self) -> Option<&StringRecord> {
+        if self.header || self.has_named_columns() {
+            self.csv_first_row.as_ref()
+        } else {
+            None
+        }
+    }
+
+    fn detect_format(&mut self, tmp_path: &Path) {
+        if let DeckOrigin::File(path) = &self.origin
+            && anki::is_anki_package(path)
+        {
+            self.note_types = anki::read_package(path, tmp_path)
+                .map(|collection| collection.note_types)
+                .unwrap_or_default();
+            self.note_type = self.note_types.first().map(|note_type| note_type.name.clone()).unwrap_or_default();
+        } else if let DeckOrigin::File(path) = &self.origin
+            && spreadsheet::is_spreadsheet(path)
+        {
+            self.sheet_names = spreadsheet::sheet_names(path).unwrap_or_default();
+            self.sheet = self.sheet_names.first().cloned().unwrap_or_default();
+        } else if self.is_structured() || self.is_markdown() || self.is_notes_folder() {
+            // The columns are read by refresh_columns
+        } else if let Ok(text) = self.read_csv_text() {
+            self.dialect = Dialect {
+                comment_prefix: self.dialect.comment_prefix.clone(),
+                ..dialect::sniff(&text)
+            };
+            self.plain_text_format = plaintext::sniff(&text);
+            // Quizlet and similar tools export .txt files
+            self.read_as_plain_text = self.file()
+                .and_then(Path::extension)
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("txt"));
+        }
+        self.refresh_columns();
+        let headers = self.csv_headers().cloned();
+        self.column_mapping.use_named_columns(headers.as_ref());
+    }
+
+    fn is_plain_text(&self) -> bool {
+        self.read_as_plain_text && self.is_text()
+    }
+
+    fn is_anki(&self) -> bool {
+        self.file().is_some_and(anki::is_anki_package)
+    }
+
+    fn is_spreadsheet(&self) -> bool {
+        self.file().is_some_and(spreadsheet::is_spreadsheet)
+    }
+
+    fn is_structured(&self) -> bool {
+        self.file().is_some_and(structured::is_structured)
+    }
+
+    fn is_markdown(&self) -> bool {
+        self.file().is_some_and(markdown::is_markdown)
+    }
+
+    fn is_notes_folder(&self) -> bool {
+        self.file().is_some_and(Path::is_dir)
+    }
+
+    /// Whether the formatting of the text can be chosen. Anki packages are
+    /// always HTML, and Markdown decks and notes always Markdown.
+    fn has_markup_choice(&self) -> bool {
+        !self.is_anki() && !self.is_markdown() && !self.is_notes_folder()
+    }
+
+    /// Whether the deck's columns always have names, whatever the header setting.
+    fn has_named_columns(&self) -> bool {
+        self.is_plain_text() || self.is_markdown() || self.is_notes_folder() || self.is_structured()
+    }
+
+    fn read_csv_text(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        match &self.origin {
+            DeckOrigin::File(path) => {
+                let (text, encoding) = charset::read_text(path, self.encoding)?;
+                self.detected_encoding = Some(encoding);
+                Ok(text)
+            }
+            DeckOrigin::Pasted(text) => Ok(text.clone()),
+        }
+    }
+
+    fn read_first_row(&mut self) -> Result<StringRecord, Box<dyn std::error::Error>> {
+        if self.is_plain_text() || self.is_markdown() || self.is_notes_folder() {
+            return Ok(StringRecord::from(plaintext::COLUMNS.to_vec()));
+        }
+        if let Some(path) = self.file()
+            && structured::is_structured(path)
+        {
+            return structured::field_names(path);
+        }
+        if let Some(path) = self.file()
+            && spreadsheet::is_spreadsheet(path)
+        {
+            return spreadsheet::read_sheet(path, &self.sheet)?
+                .into_iter()
+                .find(|(row, _)| *row >= self.first_row)
+                .map(|(_, record)| record)
+                .ok_or_else(|| "The sheet has no rows".into());
+        }
+        let text = self.read_csv_text()?;
+        deck::read_first_row(&text, &self.dialect)
+    }
+
+    fn refresh_columns(&mut self) {
+        self.csv_first_row = self.read_first_row().ok();
+        let headers = self.csv_headers().cloned();
+        self.column_mapping.retain_resolvable(headers.as_ref());
+        // Pasted text is never an Anki package, the only kind of deck that needs the temp folder
+        if matches!(self.origin, DeckOrigin::Pasted(_)) {
+            self.preview = Some(self.load(RowPolicy::Blank, Path::new("")).map_err(|e| e.to_string()));
+        }
+    }
+
+    /// The settings that depend on the kind of file: its format and the column mapping.
+    fn settings_ui(&mut self, ui: &mut egui::Ui, tmp_path: &Path) {
+        if let DeckOrigin::Pasted(text) = &mut self.origin {
+            ui.label("Paste tab- or comma-separated text, for example cells copied from a spreadsheet:");
+            let was_empty = text.is_empty();
+            let pasted = egui::ScrollArea::vertical().id_salt("pasted").max_height(150.0).show(ui, |ui| {
+                ui.add(egui::TextEdit::multiline(text).code_editor().desired_width(f32::INFINITY))
+            }).inner;
+            // Detect the format of the first paste, but keep the settings while the text is edited
+            if pasted.changed() && was_empty {
+                self.detect_format(tmp_path);
+            } else if pasted.changed() {
+                self.refresh_columns();
+            }
+        }
+        if self.is_spreadsheet() || self.is_text() {
+            if !self.is_plain_text() {
+                if ui.checkbox(&mut self.header, "Has Header").changed() {
+                    self.refresh_columns();
+                }
+                ui.label("Facade ignores the first row of the CSV if the button above is checked.");
+            }
+            if self.is_spreadsheet() {
+                self.spreadsheet_ui(ui);
+            } else {
+                self.csv_format_ui(ui, tmp_path);
+            }
+            ui.separator();
+        }
+        self.column_mapping_ui(ui);
+        ui.horizontal(|ui| {
+            ui.label("Hyphenate long words in:");
+            egui::ComboBox::from_id_salt("language")
+                .selected_text(hyphenation::language_name(&self.language).unwrap_or("Any language, anywhere"))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.language, String::new(), "Any language, anywhere");
+                    for (code, name) in hyphenation::LANGUAGES {
+                        ui.selectable_value(&mut self.language, code.to_string(), name);
+                    }
+                });
+        });
+        if self.has_markup_choice() {
+            let markup = self.markup;
+            ui.horizontal(|ui| {
+                ui.label("Formatting in the text:");
+                egui::ComboBox::from_id_salt("markup")
+                    .selected_text(match self.markup {
+                        Markup::Html => "HTML tags, like <b>bold</b>",
+                        Markup::Markdown => "Markdown, like **bold**",
+                        Markup::Plain => "None, print the text as it is",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.markup, Markup::Html, "HTML tags, like <b>bold</b>");
+                        ui.selectable_value(&mut self.markup, Markup::Markdown, "Markdown, like **bold**");
+                        ui.selectable_value(&mut self.markup, Markup::Plain, "None, print the text as it is");
+                    });
+            });
+            if self.markup != markup {
+                self.refresh_columns();
+            }
+        }
+        self.preview_ui(ui);
+    }
+
+    /// Shows the first cards of a pasted deck, so the format settings can be checked before generating.
+    fn preview_ui(&self, ui: &mut egui::Ui) {
+        const PREVIEW_CARDS: usize = 10;
+        match &self.preview {
+            Some(Ok(deck)) => {
+                ui.label(format!("Parsed {} cards with {} problems:", deck.cards.len(), deck.diagnostics.len()));
+                egui::Grid::new("preview").striped(true).show(ui, |ui| {
+                    ui.strong("Front");
+                    ui.strong("Back");
+                    ui.end_row();
+                    for card in deck.cards.iter().take(PREVIEW_CARDS) {
+                        ui.label(&card.front);
+                        ui.label(&card.back);
+                        ui.end_row();
+                    }
+                });
+                if deck.cards.len() > PREVIEW_CARDS {
+                    ui.label(format!("and {} more", deck.cards.len() - PREVIEW_CARDS));
+                }
+            }
+            Some(Err(error)) => {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
+            None => {}
+        }
+    }
+
+    fn csv_format_ui(&mut self, ui: &mut egui::Ui, tmp_path: &Path) {
+        let mut changed = false;
+        let has_file = self.file().is_some();
+        egui::Grid::new("csv_format").show(ui, |ui| {
+            if has_file {
+                ui.label("Encoding:");
+                egui::ComboBox::from_id_salt("encoding")
+                    .selected_text(match (self.encoding, self.detected_encoding) {
+                        (None, Some(detected)) => format!("Detected: {}", detected.name()),
+                        (encoding, _) => charset::ENCODINGS.iter()
+                            .find(|(e, _)| *e == encoding)
+                            .map_or("Other", |(_, name)| name)
+                            .to_string(),
+                    })
+                    .show_ui(ui, |ui| {
+                        for (encoding, name) in charset::ENCODINGS {
+                            changed |= ui.selectable_value(&mut self.encoding, encoding, name).changed();
+                        }
+                    });
+                ui.end_row();
+            }
+
+            ui.label("Read as:");
+            ui.horizontal(|ui| {
+                changed |= ui.radio_value(&mut self.read_as_plain_text, false, "CSV").changed();
+                changed |= ui.radio_value(&mut self.read_as_plain_text, true, "Plain text").changed();
+            });
+            ui.end_row();
+
+            if self.read_as_plain_text {
+                changed |= plain_text_format_ui(ui, &mut self.plain_text_format);
+                return;
+            }
+
+            ui.label("Delimiter:");
+            egui::ComboBox::from_id_salt("delimiter")
+                .selected_text(dialect::DELIMITERS.iter()
+                    .find(|(d, _)| *d == self.dialect.delimiter)
+                    .map_or("Other", |(_, name)| name))
+                .show_ui(ui, |ui| {
+                    for (delimiter, name) in dialect::DELIMITERS {
+                        changed |= ui.selectable_value(&mut self.dialect.delimiter, delimiter, name).changed();
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Quote:");
+            egui::ComboBox::from_id_salt("quote")
+                .selected_text(if self.dialect.quote == b'\'' { "Single quote (')" } else { "Double quote (\")" })
+                .show_ui(ui, |ui| {
+                    changed |= ui.selectable_value(&mut self.dialect.quote, b'"', "Double quote (\")").changed();
+                    changed |= ui.selectable_value(&mut self.dialect.quote, b'\'', "Single quote (')").changed();
+                });
+            ui.end_row();
+
+            ui.label("Quotes inside fields:");
+            egui::ComboBox::from_id_salt("escape")
+                .selected_text(if self.dialect.escape.is_some() { "Backslash escaped" } else { "Doubled" })
+                .show_ui(ui, |ui| {
+                    changed |= ui.selectable_value(&mut self.dialect.escape, None, "Doubled").changed();
+                    changed |= ui.selectable_value(&mut self.dialect.escape, Some(b'\\'), "Backslash escaped").changed();
+                });
+            ui.end_row();
+
+            ui.label("Skip lines starting with:");
+            changed |= ui.add(egui::TextEdit::singleline(&mut self.dialect.comment_prefix).desired_width(40.0)).changed();
+            ui.end_row();
+        });
+        if ui.button("Detect format").clicked() {
+            self.detect_format(tmp_path);
+        } else if changed {
+            self.refresh_columns();
+        }
+    }
+
+    fn spreadsheet_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+        egui::Grid::new("spreadsheet").show(ui, |ui| {
+            ui.label("Worksheet:");
+            egui::ComboBox::from_id_salt("sheet")
+                .selected_text(self.sheet.clone())
+                .show_ui(ui, |ui| {
+                    for name in self.sheet_names.clone() {
+                        changed |= ui.selectable_value(&mut self.sheet, name.clone(), name).changed();
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Start at row:");
+            changed |= ui.add(egui::DragValue::new(&mut self.first_row).range(1..=usize::MAX)).changed();
+            ui.end_row();
+        });
+        if changed {
+            self.refresh_columns();
+        }
+    }
+
+    fn column_mapping_ui(&mut self, ui: &mut egui::Ui) {
+        if self.is_anki() {
+            ui.horizontal(|ui| {
+                ui.label("Note type:");
+                egui::ComboBox::from_id_salt("note_type")
+                    .selected_text(self.note_type.clone())
+                    .show_ui(ui, |ui| {
+                        for note_type in &self.note_types {
+                            ui.selectable_value(&mut self.note_type, note_type.name.clone(), note_type.name.clone());
+                        }
+                    });
+            });
+            if let Some(note_type) = self.note_types.iter().find(|note_type| note_type.name == self.note_type) {
+                let fields: StringRecord = note_type.fields.iter().collect();
+                let mapping = self.note_type_mappings.entry(note_type.name.clone()).or_default();
+                column_mapping_ui(ui, &fields, true, mapping);
+            }
+        } else if let Some(first_row) = self.csv_first_row.clone() {
+            column_mapping_ui(ui, &first_row, self.header || self.has_named_columns(), &mut self.column_mapping);
+        }
+    }
+
+    /// Loads the cards of the deck, giving the ones without an ID one derived from their
+    /// text and the ones without a hyphenation language the deck's.
+    fn load(&mut self, policy: RowPolicy, tmp_path: &Path) -> Result<Deck, Box<dyn std::error::Error>> {
+        let mut deck = self.load_cards(policy, tmp_path)?;
+        deck::assign_ids(&mut deck.cards);
+        if self.has_markup_choice() {
+            for card in &mut deck.cards {
+                card.front = textutils::to_html(&card.front, self.markup);
+                card.back = textutils::to_html(&card.back, self.markup);
+            }
+        }
+        // Decks may come from anyone, so they can only format their text
+        for card in &mut deck.cards {
+            for (side, text) in [("front", &mut card.front), ("back", &mut card.back)] {
+                let (sanitized, removed) = textutils::sanitize(text);
+                if !removed.is_empty() {
+                    deck.diagnostics.push(Diagnostic::new(card.row, format!("removed {} from the {}", removed.join(", "), side)));
+                    *text = sanitized;
+                }
+            }
+        }
+        if !self.language.is_empty() {
+            for card in &mut deck.cards {
+                card.style.language.get_or_insert_with(|| self.language.clone());
+            }
+        }
+        Ok(deck)
+    }
+
+    fn load_cards(&mut self, policy: RowPolicy, tmp_path: &Path) -> Result<Deck, Box<dyn std::error::Error>> {
+        if let Some(path) = self.file() {
+            if anki::is_anki_package(path) {
+                return anki::load_anki(path, &self.note_type_mappings, policy, tmp_path);
+            }
+            if spreadsheet::is_spreadsheet(path) {
+                return spreadsheet::load_spreadsheet(path, &self.sheet, self.first_row, self.header, &self.column_mapping, policy);
+            }
+            if path.is_dir() {
+                return notes::load_notes_folder(path, &self.column_mapping, policy);
+            }
+            if structured::is_structured(path) {
+                return structured::load_structured(path, &self.column_mapping, policy);
+            }
+        }
+        let text = self.read_csv_text()?;
+        if self.is_markdown() {
+            return markdown::load_markdown(&text, &self.column_mapping, policy);
+        }
+        if self.is_plain_text() {
+            return plaintext::load_plain_text(&text, &self.plain_text_format, &self.column_mapping, policy);
+        }
+        deck::load_csv(&text, self.header, &self.dialect, &self.column_mapping, policy)
+    }
+}
+
+impl FCDS<'_> {
+    fn add_deck(&mut self, origin: DeckOrigin) {
+        self.decks.push(DeckSource::open(origin, self.tmp_path));
+        self.selected_deck = self.decks.len() - 1;
+    }
+
+    /// Lists the decks of the print run, with buttons to select, reorder and remove them.
+    fn deck_list_ui(&mut self, ui: &mut egui::Ui) {
+        let mut move_up = None;
+        let mut remove = None;
+        egui::Grid::new("decks").striped(true).show(ui, |ui| {
+            for (index, source) in self.decks.iter().enumerate() {
+                if ui.selectable_label(index == self.selected_deck, match &source.origin {
+                    DeckOrigin::File(path) => path.display().to_string(),
+                    DeckOrigin::Pasted(_) => source.title.clone(),
+                }).clicked() {
+                    self.selected_deck = index;
+                }
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(index > 0, egui::Button::new("Up").small()).clicked() {
+                        move_up = Some(index);
+                    }
+                    if ui.add_enabled(index + 1 < self.decks.len(), egui::Button::new("Down").small()).clicked() {
+                        move_up = Some(index + 1);
+                    }
+                    if ui.small_button("Remove").clicked() {
+                        remove = Some(index);
+                    }
+                });
+                ui.end_row();
+            }
+        });
+        if let Some(index) = move_up {
+            self.decks.swap(index - 1, index);
+            if self.selected_deck == index {
+                self.selected_deck -= 1;
+            } else if self.selected_deck == index - 1 {
+                self.selected_deck += 1;
+            }
+        }
+        if let Some(index) = remove {
+            self.decks.remove(index);
+            if self.selected_deck > index || self.selected_deck >= self.decks.len() {
+                self.selected_deck = self.selected_deck.saturating_sub(1);
+            }
+        }
+    }
+
+    /// The settings that apply to every deck of the print run.
+    fn run_settings_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Rows with missing or extra fields:");
+            egui::ComboBox::from_id_salt("row_policy")
+                .selected_text(match self.row_policy {
+                    RowPolicy::Skip => "Skip the row",
+                    RowPolicy::Blank => "Print a blank side",
+                    RowPolicy::Abort => "Stop with an error",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.row_policy, RowPolicy::Skip, "Skip the row");
+                    ui.selectable_value(&mut self.row_policy, RowPolicy::Blank, "Print a blank side");
+                    ui.selectable_value(&mut self.row_policy, RowPolicy::Abort, "Stop with an error");
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Text too long for a card:");
+            egui::ComboBox::from_id_salt("overflow")
+                .selected_text(match self.overflow {
+                    OverflowPolicy::Warn => "Print it and warn",
+                    OverflowPolicy::Continue => "Continue on another card",
+                    OverflowPolicy::Abort => "Stop with an error",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.overflow, OverflowPolicy::Warn, "Print it and warn");
+                    ui.selectable_value(&mut self.overflow, OverflowPolicy::Continue, "Continue on another card");
+                    ui.selectable_value(&mut self.overflow, OverflowPolicy::Abort, "Stop with an error");
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Only print cards tagged:");
+            ui.add(egui::TextEdit::singleline(&mut self.filter.tag).hint_text("any tag").desired_width(120.0));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Only print cards with IDs:");
+            ui.add(egui::TextEdit::singleline(&mut self.filter.ids).hint_text("any ID").desired_width(120.0));
+        });
+        ui.checkbox(&mut self.print_ids, "Print card IDs in the corner");
+        ui.horizontal(|ui| {
+            ui.label("Copies of a card:");
+            egui::ComboBox::from_id_salt("copies")
+                .selected_text(match self.copies {
+                    CopyPlacement::Together => "Next to each other",
+                    CopyPlacement::Spread => "Spread across sheets",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.copies, CopyPlacement::Together, "Next to each other");
+                    ui.selectable_value(&mut self.copies, CopyPlacement::Spread, "Spread across sheets");
+                });
+        });
+    }
+
+    /// Loads every deck of the print run into one list of cards, putting a divider
+    /// card in front of each deck if they are turned on, and checks the cards fit `layout`.
+    fn load_decks(&mut self, layout: &PageLayout, fonts: &mut Fonts) -> Result<Deck, Box<dyn std::error::Error>> {
+        let several = self.decks.len() > 1;
+        let mut combined = Deck::default();
+        for source in &mut self.decks {
+            let name = source.name();
+            let in_file = |e: Box<dyn std::error::Error>| -> Box<dyn std::error::Error> {
+                if several { format!("{}: {}", name, e).into() } else { e }
+            };
+            let mut deck = source.load(self.row_policy, self.tmp_path).map_err(in_file)?;
+            deck.cards.retain(|card| self.filter.matches(card));
+            // The divider is checked for overflow like the cards it comes before
+            if self.divider_cards && !deck.cards.is_empty() {
+                deck.cards.insert(0, Card {
+                    front: source.title.clone(),
+                    back: source.title.clone(),
+                    ..Card::default()
+                });
+            }
+            let mut fitted = check_overflow(std::mem::take(&mut deck.cards), layout, fonts).map_err(in_file)?;
+            deck.cards = fitted.cards;
+            deck.diagnostics.append(&mut fitted.diagnostics);
+            if several {
+                for diagnostic in &mut deck.diagnostics {
+                    // Keep the note a notes folder's diagnostic is in
+                    diagnostic.file = Some(match diagnostic.file.take() {
+                        Some(file) => format!("{}/{}", name, file),
+                        None => name.clone(),
+                    });
+                }
+            }
+            combined.diagnostics.append(&mut deck.diagnostics);
+            combined.cards.append(&mut deck.cards);
+        }
+        Ok(combined)
+    }
+}
+
+/// Adds the separator rows of a plain-text deck to a grid. Returns true if anything changed.
+fn plain_text_format_ui(ui: &mut egui::Ui, format: &mut PlainTextFormat) -> bool {
+    let mut changed = false;
+    ui.label("Term and definition separated by:");
+    ui.horizontal(|ui| {
+        let preset = plaintext::TERM_SEPARATORS.iter().find(|(separator, _)| *separator == format.term_separator);
+        egui::ComboBox::from_id_salt("term_separator")
+            .selected_text(preset.map_or("Other", |(_, name)| name))
+            .show_ui(ui, |ui| {
+                for (separator, name) in plaintext::TERM_SEPARATORS {
+                    changed |= ui.selectable_value(&mut format.term_separator, separator.to_string(), name).changed();
+                }
+                if ui.selectable_label(preset.is_none(), "Other").clicked() && preset.is_some() {
+                    format.term_separator.clear();
+                    changed = true;
+                }
+            });
+        if preset.is_none() {
+            changed |= ui.add(egui::TextEdit::singleline(&mut format.term_separator).desired_width(40.0)).changed();
+        }
+    });
+    ui.end_row();
+
+    ui.label("Cards separated by:");
+    ui.horizontal(|ui| {
+        let presets = [
+            (CardSeparator::NewLine, "New line"),
+            (CardSeparator::BlankLine, "Blank line"),
+            (CardSeparator::Custom(";".to_string()), "Semicolon (;)"),
+            (CardSeparator::Custom(";;".to_string()), "Two semicolons (;;)"),
+        ];
+        let preset = presets.iter().find(|(separator, _)| *separator == format.card_separator);
+        egui::ComboBox::from_id_salt("card_separator")
+            .selected_text(preset.map_or("Other", |(_, name)| name))
+            .show_ui(ui, |ui| {
+                for (separator, name) in presets.clone() {
+                    changed |= ui.selectable_value(&mut format.card_separator, separator, name).changed();
+                }
+                if ui.selectable_label(preset.is_none(), "Other").clicked() && preset.is_some() {
+                    format.card_separator = CardSeparator::Custom(String::new());
+                    changed = true;
+                }
+            });
+        if preset.is_none()
+            && let CardSeparator::Custom(separator) = &mut format.card_separator
+        {
+            changed |= ui.add(egui::TextEdit::singleline(separator).desired_width(40.0)).changed();
+        }
+    });
+    ui.end_row();
+    changed
+}
+
+fn column_mapping_ui(ui: &mut egui::Ui, first_row: &StringRecord, has_headers: bool, mapping: &mut ColumnMapping) {
+    let headers = if has_headers { Some(first_row) } else { None };
+    ui.label("Choose which columns go on each side of the cards, and which hold their tags, IDs and quantities:");
+    egui::Grid::new("column_mapping").striped(true).show(ui, |ui| {
+        for (index, field) in first_row.iter().enumerate() {
+            let (label, column) = if has_headers {
+                (field.to_string(), ColumnRef::Name(field.to_string()))
+            } else {
+                (format!("Column {} ({})", index + 1, field), ColumnRef::Index(index))
+            };
+            let mut side = mapping.side(index, headers);
+            ui.label(label);
+            egui::ComboBox::from_id_salt(("column_side", index))
+                .selected_text(match side {
+                    Side::Front => "Front",
+                    Side::Back => "Back",
+                    Side::Tags => "Tags",
+                    Side::Id => "ID",
+                    Side::Quantity => "Quantity",
+                    Side::Unused => "Not used",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut side, Side::Front, "Front");
+                    ui.selectable_value(&mut side, Side::Back, "Back");
+                    ui.selectable_value(&mut side, Side::Tags, "Tags");
+                    ui.selectable_value(&mut side, Side::Id, "ID");
+                    ui.selectable_value(&mut side, Side::Quantity, "Quantity");
+                    ui.selectable_value(&mut side, Side::Unused, "Not used");
+                });
+            if side != mapping.side(index, headers) {
+                mapping.set_side(column, side, headers);
+            }
+            ui.end_row();
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("Join columns on the same side with:");
+        ui.add(egui::TextEdit::singleline(&mut mapping.separator).desired_width(40.0));
+    });
+}
+
 impl eframe::App for FCDS<'_> {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        if self.error {
+        if let Some(error) = self.error.clone() {
             egui::Window::new("Error")
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
                     ui.label("An error occurred while generating flashcards.");
+                    ui.label(error);
                     if ui.button("Close").clicked() {
-                        self.error = false;
+                        self.error = None;
+                    }
+                });
+        }
+        if !self.warnings.is_empty() {
+            egui::Window::new("Warnings")
+                .collapsible(true)
+                .resizable(true)
+                .show(ctx, |ui| {
+                    egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                        for warning in &self.warnings {
+                            ui.label(warning);
+                        }
+                    });
+                    if ui.button("Close").clicked() {
+                        self.warnings.clear();
                     }
                 });
         }
         egui::CentralPanel::default().show(ctx, |ui| {
-            if let Some(path) = self.save_file_dialog.update(ctx).picked() {
-                if !self.saved {
-                    let path_buf = add_pdf_extension(path);
-                    let _ = std::fs::copy(self.tmp_path.join("flashcards.pdf"), path_buf);
-                    self.saved = true;
-                }
+            if let Some(path) = self.save_file_dialog.update(ctx).picked()
+                && !self.saved
+            {
+                let path_buf = add_pdf_extension(path);
+                let _ = std::fs::copy(self.tmp_path.join("flashcards.pdf"), path_buf);
+                self.saved = true;
             }
             self.open_file_dialog.update(ctx);
             ui.columns(2, |columns| {
-                columns[0].vertical(|ui| {
+                egui::ScrollArea::vertical().id_salt("settings").show(&mut columns[0], |ui| {
                     ui.vertical(|ui| {
                         ui.horizontal(|ui| {
-                            let file_picker_button = ui.button("Select CSV File");
+                            let file_picker_button = ui.button("Add Deck Files");
                             if file_picker_button.clicked() {
-                                self.open_file_dialog.pick_file();
+                                self.open_file_dialog.pick_multiple();
                             }
-                            if let Some(path) = self.open_file_dialog.take_picked() {
-                                self.csv_file = Some(path.to_path_buf());
+                            if ui.button("Add Notes Folder").clicked() {
+                                self.open_file_dialog.pick_directory();
                             }
-                            if let Some(path) = self.csv_file.clone() {
-                                if let Result::Ok(path_string) = path.into_os_string().into_string() {
-                                    ui.label(path_string);
-                                }
+                            if ui.button("Paste Deck").clicked() {
+                                self.add_deck(DeckOrigin::Pasted(String::new()));
+                            }
+                            for path in self.open_file_dialog.take_picked_multiple().unwrap_or_default() {
+                                self.add_deck(DeckOrigin::File(path));
+                            }
+                            if let Some(path) = self.open_file_dialog.take_picked() {
+                                self.add_deck(DeckOrigin::File(path));
                             }
                         });
-                        if !self.csv_file.is_none() {
-                            ui.checkbox(&mut self.header, "Has Header");
-                            ui.label("Facade ignores the first row of the CSV if the button above is checked.");
+                        self.deck_list_ui(ui);
+                        if self.decks.len() > 1 {
+                            ui.checkbox(&mut self.divider_cards, "Start each deck with a divider card");
+                        }
+                        let tmp_path = self.tmp_path;
+                        if let Some(source) = self.decks.get_mut(self.selected_deck) {
+                            ui.separator();
+                            if self.divider_cards {
+                                ui.horizontal(|ui| {
+                                    ui.label("Divider title:");
+                                    ui.text_edit_singleline(&mut source.title);
+                                });
+                            }
+                            source.settings_ui(ui, tmp_path);
+                            self.run_settings_ui(ui);
                         }
                     });
-                    if !self.csv_file.is_none() {
+                    if !self.decks.is_empty() {
                         ui.separator();
                         ui.label("The following can be used if your printer\nflips pages when printing double sided:");
                         ui.checkbox(&mut self.flip_horizontal, "Flip horizontal");
@@ -96,28 +915,24 @@ impl eframe::App for FCDS<'_> {
                         ui.label("Set flashcard sizes");
                         ui.add(egui::Slider::new(&mut self.num_cards_width, 0..=10).text("Flashcard Width"));
                         ui.add(egui::Slider::new(&mut self.num_cards_height, 0..=10).text("Flashcard Height"));
+                        ui.checkbox(&mut self.fit_font_size, "Fit the font size to each card");
+                        if self.fit_font_size {
+                            ui.horizontal(|ui| {
+                                ui.label("Between");
+                                ui.add(egui::DragValue::new(&mut self.min_font_size).range(1.0..=self.max_font_size).speed(0.5));
+                                ui.label("and");
+                                ui.add(egui::DragValue::new(&mut self.max_font_size).range(self.min_font_size..=200.0).speed(0.5));
+                                ui.label("pt");
+                            });
+                        }
                         ui.separator();
                         ui.label("Standard Paper Sizes:");
                         ui.horizontal(|ui| {
-                            let a4_button = ui.button("A4");
-                            if a4_button.clicked() {
-                                self.page_height = 297.0;
-                                self.page_width = 210.0;
-                            }
-                            let letter_button = ui.button("Letter");
-                            if letter_button.clicked() {
-                                self.page_width = 215.9;
-                                self.page_height = 279.4;
-                            }
-                            let legal_button = ui.button("Legal");
-                            if legal_button.clicked() {
-                                self.page_width = 215.9;
-                                self.page_height = 355.6;
-                            }
-                            let tabloid_button = ui.button("Tabloid");
-                            if tabloid_button.clicked() {
-                                self.page_width = 279.0;
-                                self.page_height = 432.0;
+                            for (name, width, height) in PAPER_SIZES {
+                                if ui.button(name).clicked() {
+                                    self.page_width = width;
+                                    self.page_height = height;
+                                }
                             }
                         });
                         ui.separator();
@@ -125,18 +940,32 @@ impl eframe::App for FCDS<'_> {
                             let gen_button = ui.button("Generate Flashcards");
                             if gen_button.clicked() {
                                 self.generated = true;
-                                if let Ok(total_pages) = gen_cards(self.page_width, self.page_height, self.num_cards_width, self.num_cards_height, self.flip_horizontal, self.flip_vertical, self.header, self.csv_file.clone(), self.tmp_path) {
-                                    self.total_pages = total_pages;
-                                } else {
-                                    self.error = true;
+                                let layout = PageLayout {
+                                    page_width: self.page_width,
+                                    page_height: self.page_height,
+                                    num_cards_width: self.num_cards_width,
+                                    num_cards_height: self.num_cards_height,
+                                    flip_horizontal: self.flip_horizontal,
+                                    flip_vertical: self.flip_vertical,
+                                    print_ids: self.print_ids,
+                                    copies: self.copies,
+                                    fit_font_size: self.fit_font_size.then_some((self.min_font_size, self.max_font_size)),
+                                    overflow: self.overflow,
+                                };
+                                let mut fonts = Fonts::load();
+                                let result = self.load_decks(&layout, &mut fonts).and_then(|deck| {
+                                    self.warnings = deck.diagnostics.iter().map(|d| d.to_string()).collect();
+                                    gen_cards(&layout, &deck.cards, self.tmp_path, &mut fonts)
+                                });
+                                match result {
+                                    Ok(total_pages) => self.total_pages = total_pages,
+                                    Err(e) => self.error = Some(e.to_string()),
                                 }
-                                if let Some(path) = self.tmp_path.join("flashcards*.png").to_str() {
-                                    if let Ok(entries) = glob(path) {
-                                        for entry in entries {
-                                            if let Some(entry) = entry.ok() {
-                                                ctx.forget_image(format!("file://{}", entry.display()).as_str());
-                                            };
-                                        }
+                                if let Some(path) = self.tmp_path.join("flashcards*.png").to_str()
+                                    && let Ok(entries) = glob(path)
+                                {
+                                    for entry in entries.flatten() {
+                                        ctx.forget_image(format!("file://{}", entry.display()).as_str());
                                     }
                                 }
                             }
@@ -171,7 +1000,7 @@ impl eframe::App for FCDS<'_> {
                             }
                         });
                     }else {
-                        if !self.csv_file.is_none() {
+                        if !self.decks.is_empty() {
                             ui.label("Press the Generate Flashcards button for a preview");
                         }
                     }
@@ -182,7 +1011,48 @@ impl eframe::App for FCDS<'_> {
     }
 }
 
+/// Lets the command line print to the console it was started from, which
+/// the window subsystem doesn't give Facade on Windows.
+#[cfg(windows)]
+fn attach_console() {
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    // Fails when started from Explorer, where there is no console to print to anyway
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_console() {}
+
 fn main() {
+    let command = cli::parse(std::env::args().skip(1));
+    if !matches!(command, Ok(cli::Command::Gui(_))) {
+        attach_console();
+    }
+    let decks = match command {
+        Ok(cli::Command::Gui(decks)) => decks,
+        Ok(cli::Command::Generate(options)) => {
+            if let Err(e) = run_headless(&options) {
+                eprintln!("facade: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("facade: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
     const ICON_BYTES: &[u8] = include_bytes!("../facadelogo.png");
 
     let icon_data = {
@@ -212,206 +1082,285 @@ fn main() {
     let temp_dir = temp_dir.path();
     let _ = eframe::run_native("Facade", frame_options, Box::new(|ctx| {
         egui_extras::install_image_loaders(&ctx.egui_ctx);
-        Ok(Box::new(FCDS{save_file_dialog: FileDialog::new(), open_file_dialog: FileDialog::new().add_file_filter("CSV files",  Arc::new(|path| path.extension().unwrap_or_default() == "csv")).default_file_filter("CSV files"), csv_file: Option::None, page_width: 215.9, page_height: 279.4, num_cards_width: 3, num_cards_height: 4, flip_horizontal: true, flip_vertical: false, preview_page: 0, total_pages: 0, error: false, saved: false, header: true, generated: false, tmp_path: temp_dir}))
+        let decks = decks.into_iter().map(|path| DeckSource::open(DeckOrigin::File(path), temp_dir)).collect();
+        Ok(Box::new(FCDS{save_file_dialog: FileDialog::new(), open_file_dialog: FileDialog::new().add_file_filter("Deck files", Arc::new(|path| is_csv(path) || spreadsheet::is_spreadsheet(path) || anki::is_anki_package(path) || structured::is_structured(path) || markdown::is_markdown(path))).add_file_filter("CSV files", Arc::new(is_csv)).add_file_filter("Spreadsheets", Arc::new(spreadsheet::is_spreadsheet)).add_file_filter("Anki packages", Arc::new(anki::is_anki_package)).add_file_filter("JSON, YAML and TOML decks", Arc::new(structured::is_structured)).add_file_filter("Markdown notes", Arc::new(markdown::is_markdown)).default_file_filter("Deck files"), decks, selected_deck: 0, divider_cards: false, row_policy: RowPolicy::Blank, overflow: OverflowPolicy::Warn, filter: CardFilter::default(), print_ids: false, copies: CopyPlacement::Together, fit_font_size: false, min_font_size: 8.0, max_font_size: 40.0, page_width: 215.9, page_height: 279.4, num_cards_width: 3, num_cards_height: 4, flip_horizontal: true, flip_vertical: false, preview_page: 0, total_pages: 0, error: None, warnings: Vec::new(), saved: false, generated: false, tmp_path: temp_dir}))
     }));
 
 }
 
-fn gen_cards(page_width: f64, page_height: f64, num_cards_width: i32, num_cards_height: i32, flip_horizontal: bool, flip_vertical: bool, headers: bool, csv_file: Option<PathBuf>, temp_dir: &Path) -> Result<i32, Box<dyn std::error::Error>>{
+/// Generates the flashcards of a command-line run and copies the PDF to the output file.
+fn run_headless(options: &cli::Options) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new("Facade")?;
+    let mut fonts = Fonts::load();
+    let mut cards = Vec::new();
+    for deck in &options.decks {
+        let origin = if deck == "-" {
+            let mut bytes = Vec::new();
+            std::io::stdin().read_to_end(&mut bytes)?;
+            DeckOrigin::Pasted(charset::decode(&bytes, None)?.0)
+        } else {
+            DeckOrigin::File(PathBuf::from(deck))
+        };
+        let mut source = DeckSource::open(origin, temp_dir.path());
+        if deck == "-" {
+            source.title = "stdin".to_string();
+        }
+        source.header = options.has_header;
+        source.language = options.language.clone();
+        source.markup = options.markup;
+        source.refresh_columns();
+        let mut deck = source.load(options.row_policy, temp_dir.path()).map_err(|e| format!("{}: {}", source.name(), e))?;
+        deck.cards.retain(|card| options.filter.matches(card));
+        let fitted = check_overflow(deck.cards, &options.layout, &mut fonts).map_err(|e| format!("{}: {}", source.name(), e))?;
+        for diagnostic in deck.diagnostics.iter().chain(&fitted.diagnostics) {
+            eprintln!("{}: {}", source.name(), diagnostic);
+        }
+        cards.extend(fitted.cards);
+    }
+    gen_cards(&options.layout, &cards, temp_dir.path(), &mut fonts)?;
+    std::fs::copy(temp_dir.path().join("flashcards.pdf"), add_pdf_extension(&options.output))?;
+    Ok(())
+}
+
+fn gen_cards(layout: &PageLayout, cards: &[Card], temp_dir: &Path, fonts: &mut Fonts) -> Result<i32, Box<dyn std::error::Error>>{
     let mut pdf_paths: Vec<String> = Vec::new();
     let svg_path = temp_dir.join("flashcards.svg");
     let mut page_num = 0;
 
-    let Some(csv_file) = csv_file else {
-        return Err("An error occurred".into())
-    };
-    let file = File::open(csv_file)?;
-    let mut reader = ReaderBuilder::new()
-        .has_headers(headers)
-        .from_reader(file);
-
-    let mut terms = Vec::new();
-    let mut definitions = Vec::new();
+    let cards_per_page = (layout.num_cards_width * layout.num_cards_height).max(0) as usize;
+    if cards_per_page == 0 {
+        return Err("The page must fit at least one flashcard".into());
+    }
+    let cards = deck::expand_copies(cards, layout.copies);
+    if cards.is_empty() {
+        return Err("The deck has no flashcards".into());
+    }
 
-    for result in reader.records() {
-        let record = result?;
+    for page in paginate(&cards, cards_per_page) {
+        write_page_svg(&svg_path, layout, page, false, fonts)?;
+        pdf_paths.push(export_page(&svg_path, temp_dir, page_num)?);
+        page_num += 1;
 
-        // Extract values from the specified columns if they exist
-        if 0 < record.len() {
-            terms.push(record[0].to_string());
-        }
+        // Now do the definitions
+        write_page_svg(&svg_path, layout, page, true, fonts)?;
+        pdf_paths.push(export_page(&svg_path, temp_dir, page_num)?);
+        page_num += 1;
+    }
+    merge_pdfs(&pdf_paths.iter().map(|x| x.as_str()).collect(), temp_dir.join("flashcards.pdf")).expect("Failed to merge PDFs");
+    Ok(page_num)
+}
 
-        if 1 < record.len() {
-            definitions.push(record[1].to_string());
+/// Splits the cards into sheets of at most `cards_per_page`, starting a new
+/// sheet at every card with a page break.
+fn paginate(cards: &[Card], cards_per_page: usize) -> Vec<&[Card]> {
+    let mut pages = Vec::new();
+    let mut start = 0;
+    for (index, card) in cards.iter().enumerate() {
+        if index > start && (index - start == cards_per_page || card.page_break) {
+            pages.push(&cards[start..index]);
+            start = index;
         }
     }
+    if start < cards.len() {
+        pages.push(&cards[start..]);
+    }
+    pages
+}
 
+/// The share of a card's width and height the text may take up, leaving a margin on both sides.
+const TEXT_SIZE: f64 = 0.8;
 
-    while !terms.is_empty() {
-        let mut svg_file = File::create(svg_path.clone())?;
-
-        // Do the header
-        write!(svg_file, "<svg width=\"{}mm\" height=\"{}mm\" version=\"1.1\" style='background-color: white;' xmlns=\"http://www.w3.org/2000/svg\">",
-               page_width, page_height)?;
+/// The distance between lines of text as a multiple of the font size.
+const LINE_HEIGHT: f64 = 1.1;
 
-        // Now the vertical lines
-        for i in 0..(num_cards_width - 1) {
-            let line_position = (i as f64 + 1.0) * (page_width / num_cards_width as f64);
-            write!(svg_file, "<line x1=\"{}mm\" y1=\"0mm\" x2=\"{}mm\" y2=\"{}mm\" stroke=\"black\" stroke-width=\"1\"/>",
-                   line_position, line_position, page_height)?;
-        }
+/// The font of cards that don't choose their own.
+const DEFAULT_FONT: &str = "Arial";
 
-        for i in 0..(num_cards_height - 1) {
-            let line_position = (i as f64 + 1.0) * (page_height / num_cards_height as f64);
-            write!(svg_file, "<line x1=\"0mm\" y1=\"{}mm\" x2=\"{}mm\" y2=\"{}mm\" stroke=\"black\" stroke-width=\"1\"/>",
-                   line_position, page_width, line_position)?;
-        }
+/// The hyphenation patterns for the card's language, if it has one.
+fn card_language(card: &Card) -> Option<hypher::Lang> {
+    card.style.language.as_deref().and_then(hyphenation::language)
+}
 
-        // Finally the text
-        for i in 0..num_cards_width {
-            for j in 0..num_cards_height {
-                if terms.is_empty() {
-                    break;
+/// Checks that the text on both sides of each card fits on it, and handles the
+/// cards that don't according to the layout's overflow policy. Continued text
+/// goes on extra cards right after the card, each showing the front marked "(cont.)",
+/// and the copies of a continued card are each followed by their own extra cards.
+fn check_overflow(cards: Vec<Card>, layout: &PageLayout, fonts: &mut Fonts) -> Result<Deck, Box<dyn std::error::Error>> {
+    let (max_width, max_height) = layout.text_area();
+    let mut deck = Deck::default();
+    for card in cards {
+        let metrics = fonts.metrics(card.style.font.as_deref().unwrap_or(DEFAULT_FONT));
+        // The lines of each side, split into the parts that fit on one card
+        let mut parts: [Vec<String>; 2] = Default::default();
+        for (back, side) in [(false, "front"), (true, "back")] {
+            let font_size = layout.font_size(&card, back, metrics);
+            let text = if back { &card.back } else { &card.front };
+            let lines = textutils::wrap_text(text, max_width, font_size, metrics, card_language(&card));
+            let fitting = ((max_height / (font_size * fonts::MM_PER_PT * LINE_HEIGHT)).floor() as usize).max(1);
+            if lines.len() <= fitting {
+                continue;
+            }
+            // A card without a row, such as a divider, is named by its front instead
+            let side = if card.row == 0 { format!("the {} of \"{}\"", side, card.front) } else { format!("the {}", side) };
+            let message = format!("{} needs {} lines but only {} fit on the card", side, lines.len(), fitting);
+            match layout.overflow {
+                OverflowPolicy::Warn => deck.diagnostics.push(Diagnostic::new(card.row, message)),
+                OverflowPolicy::Abort => return Err(Diagnostic::new(card.row, message).to_string().into()),
+                // Leave room for the mark on the continued fronts
+                OverflowPolicy::Continue => {
+                    let per_card = if back { fitting } else { (fitting - 1).max(1) };
+                    parts[back as usize] = textutils::split_text(text, max_width, font_size, metrics, card_language(&card), per_card);
                 }
-                // Calculate center position
-                let center_x = (i as f64 * (page_width / num_cards_width as f64) +
-                    (i as f64 + 1.0) * (page_width / num_cards_width as f64)) / 2.0;
-                let center_y = (j as f64 * (page_height / num_cards_height as f64) +
-                    (j as f64 + 1.0) * (page_height / num_cards_height as f64)) / 2.0;
-
-                // Write the text
-
-                let text_element = textutils::generate_centered_text_element(
-                    &terms[0],
-                    center_x,
-                    center_y,
-                    (0.153 * page_width / num_cards_width as f64).floor() as usize,
-                    30.0,
-                    1.1,
-                    "Arial");
-
-                write!(svg_file, "{}", text_element)?;
-                terms.remove(0);
             }
         }
 
-        // End the svg
-        write!(svg_file, "</svg>")?;
-        svg_file.flush()?;
-
-
-        if convert_svg_to_png(svg_path.clone(), temp_dir.join(format!("flashcards{}.png",page_num))).is_err() {
-            return Err("An error occurred".into());
+        let [fronts, backs] = parts;
+        let count = fronts.len().max(backs.len()).max(1);
+        let mut continued = Vec::with_capacity(count);
+        for index in 0..count {
+            let front = fronts.get(index).cloned();
+            let back = backs.get(index).cloned();
+            if index == 0 {
+                continued.push(Card {
+                    front: front.unwrap_or_else(|| card.front.clone()),
+                    back: back.unwrap_or_else(|| card.back.clone()),
+                    ..card.clone()
+                });
+            } else {
+                continued.push(Card {
+                    front: match front {
+                        Some(front) => format!("(cont.) {}", front),
+                        None => format!("{} (cont.)", card.front),
+                    },
+                    back: back.unwrap_or_default(),
+                    id: format!("{}-cont{}", card.id, index),
+                    page_break: false,
+                    ..card.clone()
+                });
+            }
         }
-
-        // Convert SVG to PDF
-        let svg = std::fs::read_to_string(temp_dir.join("flashcards.svg")).unwrap();
-        let mut options = svg2pdf::usvg::Options::default();
-        options.fontdb_mut().load_system_fonts();
-        let tree = svg2pdf::usvg::Tree::from_str(&svg, &options).unwrap();
-
-        let pdf_data = svg2pdf::to_pdf(&tree, ConversionOptions::default(), PageOptions::default()).map_err(|e| e.to_string())?;
-        let pdf_path = temp_dir.join(format!("flashcards{}.pdf", page_num));
-        std::fs::write(pdf_path.clone(), pdf_data).unwrap();
-        if let Some(pdf_path) = pdf_path.to_str() {
-            pdf_paths.push(pdf_path.to_string());
+        if count == 1 {
+            deck.cards.append(&mut continued);
+            continue;
         }
+        // Every copy of a continued card gets its own continuations right after it,
+        // rather than leaving them to be placed apart as copies of their own
+        for copy in 0..card.quantity.unwrap_or(1) {
+            deck.cards.extend(continued.iter().map(|part| Card {
+                quantity: None,
+                page_break: part.page_break && copy == 0,
+                ..part.clone()
+            }));
+        }
+    }
+    Ok(deck)
+}
 
+/// Writes the fronts (or the backs) of the cards on one sheet to `svg_path`.
+/// Card `n` sits in column `n / num_cards_height` and row `n % num_cards_height`,
+/// mirrored on the back when the page is flipped so both sides line up.
+fn write_page_svg(svg_path: &Path, layout: &PageLayout, cards: &[Card], back: bool, fonts: &mut Fonts) -> Result<(), Box<dyn std::error::Error>> {
+    let PageLayout { page_width, page_height, num_cards_width, num_cards_height, flip_horizontal, flip_vertical, print_ids, .. } = *layout;
+    let mut svg_file = File::create(svg_path)?;
+
+    // Do the header
+    write!(svg_file, "<svg width=\"{}mm\" height=\"{}mm\" version=\"1.1\" style='background-color: white;' xmlns=\"http://www.w3.org/2000/svg\">",
+           page_width, page_height)?;
+
+    // Now the vertical lines
+    for i in 0..(num_cards_width - 1) {
+        let line_position = (i as f64 + 1.0) * (page_width / num_cards_width as f64);
+        write!(svg_file, "<line x1=\"{}mm\" y1=\"0mm\" x2=\"{}mm\" y2=\"{}mm\" stroke=\"black\" stroke-width=\"1\"/>",
+               line_position, line_position, page_height)?;
+    }
 
-        page_num += 1;
-
-        // Now do the definitions
-        let mut svg_file = File::create(svg_path.clone())?;
-
-        // Do the header
-        write!(svg_file, "<svg width=\"{}mm\" height=\"{}mm\" version=\"1.1\" style='background-color: white;' xmlns=\"http://www.w3.org/2000/svg\">",
-               page_width, page_height)?;
+    for i in 0..(num_cards_height - 1) {
+        let line_position = (i as f64 + 1.0) * (page_height / num_cards_height as f64);
+        write!(svg_file, "<line x1=\"0mm\" y1=\"{}mm\" x2=\"{}mm\" y2=\"{}mm\" stroke=\"black\" stroke-width=\"1\"/>",
+               line_position, page_width, line_position)?;
+    }
 
-        // Now the vertical lines
-        for i in 0..(num_cards_width - 1) {
-            let line_position = (i as f64 + 1.0) * (page_width / num_cards_width as f64);
-            write!(svg_file, "<line x1=\"{}mm\" y1=\"0mm\" x2=\"{}mm\" y2=\"{}mm\" stroke=\"black\" stroke-width=\"1\"/>",
-                   line_position, line_position, page_height)?;
+    // Finally the text
+    for (index, card) in cards.iter().enumerate() {
+        let mut i = index as i32 / num_cards_height;
+        let mut j = index as i32 % num_cards_height;
+        if back && flip_horizontal {
+            i = num_cards_width - 1 - i;
         }
-
-        for i in 0..(num_cards_height - 1) {
-            let line_position = (i as f64 + 1.0) * (page_height / num_cards_height as f64);
-            write!(svg_file, "<line x1=\"0mm\" y1=\"{}mm\" x2=\"{}mm\" y2=\"{}mm\" stroke=\"black\" stroke-width=\"1\"/>",
-                   line_position, page_width, line_position)?;
+        if back && flip_vertical {
+            j = num_cards_height - 1 - j;
         }
-        let width_range: Box<dyn Iterator<Item = i32>> = if flip_horizontal {
-            Box::new((0..num_cards_width).rev())
-        } else {
-            Box::new(0..num_cards_width)
-        };
 
-        // Finally the text
-        for i in width_range {
-            let height_range: Box<dyn Iterator<Item = i32>> = if flip_vertical {
-                Box::new((0..num_cards_height).rev())
-            } else {
-                Box::new(0..num_cards_height)
-            };
-            for j in height_range {
-                if definitions.is_empty() {
-                    break;
-                }
-                // Calculate center position
-                let center_x = (i as f64 * (page_width / num_cards_width as f64) +
-                    (i as f64 + 1.0) * (page_width / num_cards_width as f64)) / 2.0;
-                let center_y = (j as f64 * (page_height / num_cards_height as f64) +
-                    (j as f64 + 1.0) * (page_height / num_cards_height as f64)) / 2.0;
-
-                //Write the text
-
-                let text_element = textutils::generate_centered_text_element(
-                    &definitions[0],
-                    center_x,
-                    center_y,
-                    (0.37054191755 * page_width / num_cards_width as f64).floor() as usize,
-                    12.0,
-                    1.1,
-                    "Arial"
-                );
-
-                write!(svg_file, "{}", text_element)?;
-                definitions.remove(0);
-            }
+        // Calculate center position
+        let center_x = (i as f64 * (page_width / num_cards_width as f64) +
+            (i as f64 + 1.0) * (page_width / num_cards_width as f64)) / 2.0;
+        let center_y = (j as f64 * (page_height / num_cards_height as f64) +
+            (j as f64 + 1.0) * (page_height / num_cards_height as f64)) / 2.0;
+
+        // Write the text, wrapped to the width of the card in the card's font
+        let font = card.style.font.as_deref().unwrap_or(DEFAULT_FONT);
+        let metrics = fonts.metrics(font);
+        let text = if back { &card.back } else { &card.front };
+        let font_size = layout.font_size(card, back, metrics);
+        let text_element = textutils::generate_centered_text_element(
+            text,
+            center_x,
+            center_y,
+            layout.text_area().0,
+            font_size,
+            LINE_HEIGHT,
+            font,
+            metrics,
+            card_language(card));
+
+        write!(svg_file, "{}", text_element)?;
+
+        if print_ids && !card.id.is_empty() {
+            write!(svg_file, "<text x=\"{}mm\" y=\"{}mm\" style=\"font-size:6pt;font-family:Arial;text-anchor:end\" fill=\"gray\">{}</text>",
+                   (i as f64 + 1.0) * (page_width / num_cards_width as f64) - 2.0,
+                   (j as f64 + 1.0) * (page_height / num_cards_height as f64) - 2.0,
+                   textutils::escape_xml(&card.id))?;
         }
+    }
 
-        // End the svg
-        write!(svg_file, "</svg>").expect("Failed to write SVG closing tag");
-        svg_file.flush().expect("Failed to flush SVG file");
+    // End the svg
+    write!(svg_file, "</svg>")?;
+    svg_file.flush()?;
+    Ok(())
+}
 
-        if convert_svg_to_png(svg_path.clone(), temp_dir.join(format!("flashcards{}.png",page_num))).is_err() {
-            return Err("An error occurred".into());
-        }
-        // Convert SVG to PDF
-        let svg = std::fs::read_to_string(temp_dir.join("flashcards.svg")).unwrap();
-        let mut options = svg2pdf::usvg::Options::default();
-        options.fontdb_mut().load_system_fonts();
-        let tree = svg2pdf::usvg::Tree::from_str(&svg, &options).unwrap();
+/// Renders the SVG at `svg_path` to the preview PNG and the PDF of page `page_num`,
+/// returning the path of the PDF.
+fn export_page(svg_path: &Path, temp_dir: &Path, page_num: i32) -> Result<String, Box<dyn std::error::Error>> {
+    convert_svg_to_png(svg_path.to_path_buf(), temp_dir.join(format!("flashcards{}.png",page_num)))
+        .map_err(|e| format!("Couldn't draw page {}: {}", page_num + 1, e))?;
+
+    // Convert SVG to PDF
+    let svg = std::fs::read_to_string(svg_path)?;
+    let mut options = svg2pdf::usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+    let tree = svg2pdf::usvg::Tree::from_str(&svg, &options)
+        .map_err(|e| format!("Couldn't read page {} back for the PDF: {}", page_num + 1, e))?;
+
+    let pdf_data = svg2pdf::to_pdf(&tree, ConversionOptions::default(), PageOptions::default()).map_err(|e| e.to_string())?;
+    let pdf_path = temp_dir.join(format!("flashcards{}.pdf", page_num));
+    std::fs::write(pdf_path.clone(), pdf_data)?;
+    Ok(pdf_path.to_string_lossy().into_owned())
+}
 
-        let pdf_data = svg2pdf::to_pdf(&tree, ConversionOptions::default(), PageOptions::default()).map_err(|e| e.to_string())?;
-        let pdf_path = temp_dir.join(format!("flashcards{}.pdf", page_num));
-        std::fs::write(pdf_path.clone(), pdf_data).unwrap();
-        if let Some(pdf_path) = pdf_path.to_str() {
-            pdf_paths.push(pdf_path.to_string());
-        }
-        page_num += 1;
-    }
-    merge_pdfs(&pdf_paths.iter().map(|x| x.as_str()).collect(), temp_dir.join("flashcards.pdf")).expect("Failed to merge PDFs");
-    return Ok(page_num);
+fn is_csv(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default();
+    ["csv", "tsv", "txt"].iter().any(|ext| extension.eq_ignore_ascii_case(ext))
 }
 
 fn add_pdf_extension(path: &Path) -> PathBuf {
 
     let path_ref = path;
     // Check if the path already has a .pdf extension (case insensitive)
-    if let Some(ext) = path_ref.extension() {
-        if ext.to_ascii_lowercase() == "pdf" {
-            return path_ref.to_path_buf();
-        }
+    if let Some(ext) = path_ref.extension()
+        && ext.eq_ignore_ascii_case("pdf")
+    {
+        return path_ref.to_path_buf();
     }
 
     // Add .pdf extension
@@ -439,16 +1388,16 @@ fn merge_pdfs(pdfs: &Vec<&str>, output_path: PathBuf) -> Result<(), Error> {
 
     // If only one PDF, just copy it to output
     if pdfs.len() == 1 {
-        let mut doc = Document::load(pdfs[0]).map_err(|e| {return std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to load PDF: {}", e))})?;
+        let mut doc = Document::load(pdfs[0]).map_err(|e| {std::io::Error::other(format!("Failed to load PDF: {}", e))})?;
         let _ = doc.save(output_path).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to save PDF: {}", e))
+            std::io::Error::other(format!("Failed to save PDF: {}", e))
         });
         return Ok(())
     }
 
     // Load all documents
     let mut documents = Vec::with_capacity(pdfs.len());
-    for &pdf_path in pdfs.into_iter() {
+    for &pdf_path in pdfs.iter() {
         match Document::load(pdf_path) {
             Ok(doc) => documents.push(doc),
             Err(e) => return Err(std::io::Error::new(
@@ -498,7 +1447,7 @@ fn merge_pdfs(pdfs: &Vec<&str>, output_path: PathBuf) -> Result<(), Error> {
                     all_pages
                         .keys()
                         .cloned()
-                        .map(|key| Object::Reference(key))
+                        .map(Object::Reference)
                         .collect(),
                 ),
             ),
@@ -526,7 +1475,7 @@ fn merge_pdfs(pdfs: &Vec<&str>, output_path: PathBuf) -> Result<(), Error> {
 
     // Save the merged document
     let _ = merged_doc.save(output_path).map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to save merged PDF: {}", e))
+        std::io::Error::other(format!("Failed to save merged PDF: {}", e))
     });
     Ok(())
 }
@@ -543,8 +1492,10 @@ fn convert_svg_to_png(svg_path: PathBuf, png_path: PathBuf) -> Result<(), Box<dy
 
     let fontdb_arc = Arc::new(fontdb);
 
-    let mut opt = svg2pdf::usvg::Options::default();
-    opt.fontdb = fontdb_arc.clone();
+    let opt = svg2pdf::usvg::Options {
+        fontdb: fontdb_arc.clone(),
+        ..Default::default()
+    };
     // Parse SVG using usvg::Tree::from_str
     let tree = svg2pdf::usvg::Tree::from_str(
         &svg_content,
@@ -573,6 +1524,3 @@ fn convert_svg_to_png(svg_path: PathBuf, png_path: PathBuf) -> Result<(), Box<dy
 
     Ok(())
 }
-
-
-
@@ -0,0 +1,144 @@
+use crate::deck::{self, ColumnMapping, Deck, RowPolicy};
+use crate::plaintext;
+use crate::textutils::{self, Markup};
+use csv::StringRecord;
+use std::path::Path;
+
+/// The file extensions of Markdown decks.
+pub const EXTENSIONS: [&str; 2] = ["md", "markdown"];
+
+pub fn is_markdown(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default();
+    EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))
+}
+
+/// A card found in the notes, before the mapping is applied.
+struct Entry {
+    row: usize,
+    term: String,
+    definitions: Vec<String>,
+    section: Option<String>,
+}
+
+/// Loads a deck from Markdown notes. A `## heading` starts a section, which is
+/// added to the cards below it as a tag. Cards are either `**term**: definition`
+/// lines or definition lists:
+///
+/// ```markdown
+/// Term
+/// : definition
+/// ```
+///
+/// The term and definition are mapped to the sides like the columns of a plain-text deck.
+pub fn load_markdown(text: &str, mapping: &ColumnMapping, policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+    let entries = parse(text);
+    let headers = StringRecord::from(plaintext::COLUMNS.to_vec());
+    let records = entries.iter().map(|entry| {
+        let definition = entry.definitions.join("; ");
+        Ok((entry.row, StringRecord::from(vec![textutils::to_html(&entry.term, Markup::Markdown), textutils::to_html(&definition, Markup::Markdown)])))
+    });
+    let mut deck = deck::cards_from_records(Some(&headers), records, mapping, policy, false)?;
+    // Each entry makes exactly one card, so they line up
+    for (card, entry) in deck.cards.iter_mut().zip(&entries) {
+        card.tags.extend(entry.section.clone());
+    }
+    Ok(deck)
+}
+
+fn parse(text: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut section: Option<String> = None;
+    let mut in_code = false;
+    // The last paragraph line, which becomes the term if a definition follows it
+    let mut previous: Option<(usize, &str)> = None;
+    // Whether a definition may still be added to the last entry
+    let mut in_definitions = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let row = index + 1;
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            previous = None;
+            in_definitions = false;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        if trimmed.is_empty() {
+            // A blank line may separate a term from its definitions
+            continue;
+        }
+
+        if let Some(heading) = heading(trimmed) {
+            match heading {
+                (1, _) => section = None,
+                (2, title) => section = Some(title.to_string()),
+                _ => {}
+            }
+            previous = None;
+            in_definitions = false;
+        } else if let Some(definition) = definition(line) {
+            if let Some((row, term)) = previous.take() {
+                entries.push(Entry {
+                    row,
+                    term: term.to_string(),
+                    definitions: vec![definition.to_string()],
+                    section: section.clone(),
+                });
+                in_definitions = true;
+            } else if in_definitions && let Some(entry) = entries.last_mut() {
+                entry.definitions.push(definition.to_string());
+            }
+        } else if let Some((term, definition)) = bold_term(trimmed) {
+            entries.push(Entry {
+                row,
+                term: term.to_string(),
+                definitions: vec![definition.to_string()],
+                section: section.clone(),
+            });
+            previous = None;
+            in_definitions = false;
+        } else {
+            previous = Some((row, trimmed));
+            in_definitions = false;
+        }
+    }
+    entries
+}
+
+/// The level and title of an ATX heading such as `## Title`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let rest = &line[level..];
+    if level == 0 || level > 6 || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim()))
+}
+
+/// The text of a definition line, `: definition`, indented by at most three spaces.
+fn definition(line: &str) -> Option<&str> {
+    let content = line.trim_start();
+    if line.len() - content.len() > 3 {
+        return None;
+    }
+    content.strip_prefix(':').filter(|rest| rest.starts_with([' ', '\t'])).map(str::trim)
+}
+
+/// Splits a `**term**: definition` line, which may also be a list item.
+fn bold_term(line: &str) -> Option<(&str, &str)> {
+    let line = ["- ", "* ", "+ "].iter()
+        .find_map(|marker| line.strip_prefix(marker))
+        .map_or(line, str::trim_start);
+    let rest = line.strip_prefix("**")?;
+    let end = rest.find("**")?;
+    let (term, after) = (&rest[..end], &rest[end + 2..]);
+    // Accept both **term**: definition and **term:** definition
+    if let Some(definition) = after.strip_prefix(':') {
+        return Some((term.trim(), definition.trim()));
+    }
+    let term = term.trim_end().strip_suffix(':')?;
+    Some((term.trim(), after.trim()))
+}
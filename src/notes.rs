@@ -0,0 +1,199 @@
+use crate::charset;
+use crate::deck::{self, Card, ColumnMapping, Deck, RowPolicy};
+use crate::plaintext;
+use crate::textutils::{self, Markup};
+use csv::StringRecord;
+use std::path::{Path, PathBuf};
+
+/// Logseq properties are written `key:: value` like cards, so these keys aren't
+/// taken for questions. Keys starting with `card-`, `query-` or `hl-` are skipped too.
+const PROPERTIES: [&str; 14] = [
+    "id", "tags", "alias", "aliases", "title", "type", "collapsed", "public",
+    "filters", "icon", "template", "template-including-parent", "heading", "background-color",
+];
+
+/// A card found in the notes, before the mapping is applied.
+struct Entry {
+    /// The file the card is in, relative to the folder.
+    file: String,
+    row: usize,
+    question: String,
+    answer: String,
+    /// Also print the card the other way round, for `Q:::A`.
+    reversed: bool,
+    tags: Vec<String>,
+}
+
+/// The Markdown files of a notes folder in a stable order, leaving out hidden
+/// folders such as `.obsidian` and `.trash` and Logseq's own `logseq` folder.
+fn note_files(folder: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let pattern = format!("{}/**/*.md", glob::Pattern::escape(&folder.to_string_lossy()));
+    let mut files: Vec<PathBuf> = glob::glob(&pattern)?
+        .flatten()
+        .filter(|path| {
+            let relative = path.strip_prefix(folder).unwrap_or(path);
+            let mut components = relative.components().map(|c| c.as_os_str().to_string_lossy());
+            let hidden = components.clone().any(|c| c.starts_with('.'));
+            !hidden && components.next().is_none_or(|first| first != "logseq")
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Loads the inline `question::answer` and `question:::answer` cards of every
+/// Markdown file in a notes folder. Each card is tagged with its file, relative
+/// to the folder and without the extension, and with the heading it is under.
+/// The question and answer are mapped to the sides like the columns of a plain-text deck.
+pub fn load_notes_folder(folder: &Path, mapping: &ColumnMapping, policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+    let mut entries = Vec::new();
+    for path in note_files(folder)? {
+        let (text, _) = charset::read_text(&path, None)?;
+        let file = path.strip_prefix(folder).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+        entries.extend(parse(&text, &file));
+    }
+    if entries.is_empty() {
+        return Err(format!("No question::answer cards found in {}", folder.display()).into());
+    }
+
+    let headers = StringRecord::from(plaintext::COLUMNS.to_vec());
+    let records = entries.iter().enumerate().map(|(index, entry)| {
+        let record = StringRecord::from(vec![textutils::to_html(&entry.question, Markup::Markdown), textutils::to_html(&entry.answer, Markup::Markdown)]);
+        Ok((index, record))
+    });
+    let mapped = deck::cards_from_records(Some(&headers), records, mapping, policy, false)?;
+
+    // The records are numbered by their place among all the notes' cards
+    let mut deck = Deck {
+        cards: Vec::new(),
+        diagnostics: mapped.diagnostics.into_iter()
+            .map(|diagnostic| match entries.get(diagnostic.row) {
+                Some(entry) => deck::Diagnostic { file: Some(entry.file.clone()), row: entry.row, ..diagnostic },
+                None => diagnostic,
+            })
+            .collect(),
+    };
+    for card in mapped.cards {
+        let entry = &entries[card.row];
+        let card = Card {
+            row: entry.row,
+            tags: entry.tags.clone(),
+            ..card
+        };
+        if entry.reversed {
+            let reversed = Card {
+                front: card.back.clone(),
+                back: card.front.clone(),
+                ..card.clone()
+            };
+            deck.cards.push(card);
+            deck.cards.push(reversed);
+        } else {
+            deck.cards.push(card);
+        }
+    }
+    Ok(deck)
+}
+
+fn parse(text: &str, file: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut heading: Option<String> = None;
+    let mut in_code = false;
+    let mut lines = text.lines().enumerate().peekable();
+
+    // Skip YAML front matter
+    if lines.peek().is_some_and(|(_, line)| line.trim_end() == "---") {
+        lines.next();
+        for (_, line) in lines.by_ref() {
+            if line.trim_end() == "---" {
+                break;
+            }
+        }
+    }
+
+    for (index, line) in lines {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            continue;
+        }
+        // Logseq writes headings as blocks, `- ## Heading`
+        let block = trimmed.strip_prefix("- ").unwrap_or(trimmed);
+        let title = block.trim_start_matches('#');
+        if block.starts_with('#') && (title.is_empty() || title.starts_with(' ')) {
+            heading = Some(title.trim().to_string()).filter(|title| !title.is_empty());
+            continue;
+        }
+        let Some((question, answer, reversed)) = split_card(trimmed) else {
+            continue;
+        };
+        let mut tags = vec![file.strip_suffix(".md").unwrap_or(file).to_string()];
+        tags.extend(heading.clone());
+        entries.push(Entry {
+            file: file.to_string(),
+            row: index + 1,
+            question: question.to_string(),
+            answer,
+            reversed,
+            tags,
+        });
+    }
+    entries
+}
+
+/// Splits a `question::answer` or `question:::answer` line. List markers,
+/// quote markers and the scheduling comments spaced-repetition plugins add are removed.
+fn split_card(line: &str) -> Option<(&str, String, bool)> {
+    let mut line = line;
+    while let Some(rest) = ["- ", "* ", "+ ", "> "].iter().find_map(|marker| line.strip_prefix(marker)) {
+        line = rest.trim_start();
+    }
+    let start = separator_at(line)?;
+    let question = line[..start].trim();
+    let rest = &line[start..];
+    let (answer, reversed) = match rest.strip_prefix(":::") {
+        Some(answer) => (answer, true),
+        None => (&rest[2..], false),
+    };
+    let answer = strip_comments(answer);
+    let answer = answer.trim();
+    if question.is_empty() || answer.is_empty() || is_property(question) {
+        return None;
+    }
+    Some((question, answer.to_string(), reversed))
+}
+
+/// The position of the first `::` outside inline code.
+fn separator_at(line: &str) -> Option<usize> {
+    let mut in_code = false;
+    for (index, c) in line.char_indices() {
+        if c == '`' {
+            in_code = !in_code;
+        } else if !in_code && line[index..].starts_with("::") {
+            return Some(index);
+        }
+    }
+    None
+}
+
+fn is_property(key: &str) -> bool {
+    key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        && (PROPERTIES.contains(&key) || ["card-", "query-", "hl-"].iter().any(|prefix| key.starts_with(prefix)))
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("<!--") {
+        output.push_str(&rest[..start]);
+        rest = match rest[start..].find("-->") {
+            Some(end) => &rest[start + end + 3..],
+            None => "",
+        };
+    }
+    output.push_str(rest);
+    output
+}
@@ -0,0 +1,208 @@
+use crate::deck::{self, ColumnMapping, Deck, RowPolicy};
+use csv::StringRecord;
+
+/// The column names plain-text cards are mapped with.
+pub const COLUMNS: [&str; 2] = ["Term", "Definition"];
+
+/// Separators between a term and its definition offered in the GUI.
+pub const TERM_SEPARATORS: [(&str, &str); 6] = [
+    ("\t", "Tab"),
+    (" - ", "Dash ( - )"),
+    (",", "Comma"),
+    (":", "Colon"),
+    (" = ", "Equals ( = )"),
+    ("\n", "New line"),
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CardSeparator {
+    NewLine,
+    /// One or more empty lines, so a card can span several lines.
+    BlankLine,
+    Custom(String),
+}
+
+/// How a plain-text deck such as a Quizlet export is laid out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PlainTextFormat {
+    /// Splits a card into term and definition at its first occurrence.
+    pub term_separator: String,
+    pub card_separator: CardSeparator,
+}
+
+impl Default for PlainTextFormat {
+    fn default() -> Self {
+        // Quizlet's default export
+        PlainTextFormat {
+            term_separator: "\t".to_string(),
+            card_separator: CardSeparator::NewLine,
+        }
+    }
+}
+
+/// Guesses the separators of a plain-text deck.
+pub fn sniff(text: &str) -> PlainTextFormat {
+    let card_separator = if text.contains(";;") {
+        CardSeparator::Custom(";;".to_string())
+    } else if spans_lines(&split_cards(text, &CardSeparator::BlankLine)) {
+        CardSeparator::BlankLine
+    } else {
+        CardSeparator::NewLine
+    };
+    let cards = split_cards(text, &card_separator);
+
+    // Use the separator that appears in the most cards
+    let term_separator = TERM_SEPARATORS.iter()
+        .map(|(separator, _)| *separator)
+        .filter(|separator| *separator != "\n" || card_separator != CardSeparator::NewLine)
+        .max_by_key(|separator| {
+            let count = cards.iter().filter(|(_, card)| card.contains(separator)).count();
+            // On ties prefer the separators listed first
+            (count, std::cmp::Reverse(TERM_SEPARATORS.iter().position(|(s, _)| s == separator)))
+        })
+        .unwrap_or("\t");
+
+    PlainTextFormat {
+        term_separator: term_separator.to_string(),
+        card_separator,
+    }
+}
+
+/// True if there are several cards and most span several lines, so a file
+/// without empty lines or with a stray one between one-line cards isn't
+/// taken for blank-line separated.
+fn spans_lines(cards: &[(usize, &str)]) -> bool {
+    let multiline = cards.iter()
+        .filter(|(_, card)| card.contains('\n') && !one_card_per_line(card))
+        .count();
+    cards.len() > 1 && multiline * 2 > cards.len()
+}
+
+/// True if every line has the same term separator, so the text is a run
+/// of one-line cards rather than a single card.
+fn one_card_per_line(text: &str) -> bool {
+    TERM_SEPARATORS.iter()
+        .filter(|(separator, _)| *separator != "\n")
+        .any(|(separator, _)| text.lines().all(|line| line.contains(separator)))
+}
+
+/// Splits the text into cards, each with the line it starts on (starting at 1).
+fn split_cards<'a>(text: &'a str, separator: &CardSeparator) -> Vec<(usize, &'a str)> {
+    let mut chunks: Vec<(usize, &str)> = Vec::new();
+    match separator {
+        CardSeparator::NewLine => {
+            let mut offset = 0;
+            for line in text.split('\n') {
+                chunks.push((offset, line));
+                offset += line.len() + 1;
+            }
+        }
+        CardSeparator::BlankLine => {
+            let mut offset = 0;
+            let mut start: Option<usize> = None;
+            for line in text.split_inclusive('\n') {
+                if line.trim().is_empty() {
+                    if let Some(start) = start.take() {
+                        chunks.push((start, &text[start..offset]));
+                    }
+                } else if start.is_none() {
+                    start = Some(offset);
+                }
+                offset += line.len();
+            }
+            if let Some(start) = start {
+                chunks.push((start, &text[start..]));
+            }
+        }
+        CardSeparator::Custom(separator) => {
+            let mut offset = 0;
+            for chunk in text.split(separator.as_str()) {
+                chunks.push((offset, chunk));
+                offset += chunk.len() + separator.len();
+            }
+        }
+    }
+
+    chunks.into_iter()
+        .filter(|(_, chunk)| !chunk.trim().is_empty())
+        .map(|(offset, chunk)| {
+            // Count the line the card's text starts on, not the whitespace before it
+            let leading = chunk.len() - chunk.trim_start().len();
+            let row = text[..offset + leading].matches('\n').count() + 1;
+            (row, chunk.trim())
+        })
+        .collect()
+}
+
+/// Loads a deck where each card is a term and a definition separated by
+/// `format.term_separator`. Cards without the separator have no definition
+/// and are handled according to `policy`.
+pub fn load_plain_text(text: &str, format: &PlainTextFormat, mapping: &ColumnMapping, policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+    if format.term_separator.is_empty() {
+        return Err("The separator between term and definition is empty".into());
+    }
+    if format.term_separator == "\n" && format.card_separator == CardSeparator::NewLine {
+        return Err("Terms and cards can't both be separated by new lines".into());
+    }
+    let headers = StringRecord::from(COLUMNS.to_vec());
+    let records = split_cards(text, &format.card_separator)
+        .into_iter()
+        .map(|(row, card)| {
+            let record = match card.split_once(format.term_separator.as_str()) {
+                Some((term, definition)) => StringRecord::from(vec![term.trim(), definition.trim()]),
+                None => StringRecord::from(vec![card]),
+            };
+            Ok((row, record))
+        });
+    deck::cards_from_records(Some(&headers), records, mapping, policy, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(term_separator: &str, card_separator: CardSeparator) -> PlainTextFormat {
+        PlainTextFormat { term_separator: term_separator.to_string(), card_separator }
+    }
+
+    #[test]
+    fn sniff_blank_line_separated_cards() {
+        let text = "Hund\ndog, a pet\n\nKatze\ncat\n\nMaus\nmouse\n";
+        assert_eq!(sniff(text), format("\n", CardSeparator::BlankLine));
+    }
+
+    #[test]
+    fn sniff_one_card_per_line() {
+        let text = "Hund\tdog\nKatze\tcat\nMaus\tmouse\n";
+        assert_eq!(sniff(text), format("\t", CardSeparator::NewLine));
+        assert_eq!(sniff(&text.replace('\n', "\r\n")), format("\t", CardSeparator::NewLine));
+        assert_eq!(sniff("Hund - dog\nKatze - cat\nMaus - mouse"), format(" - ", CardSeparator::NewLine));
+    }
+
+    #[test]
+    fn sniff_ignores_a_stray_blank_line() {
+        let text = "Hund\tdog\nKatze\tcat\n\nMaus\tmouse\nVogel\tbird\n";
+        assert_eq!(sniff(text), format("\t", CardSeparator::NewLine));
+    }
+
+    #[test]
+    fn sniff_custom_card_separator() {
+        assert_eq!(sniff("Hund - dog;;Katze - cat;;Maus - mouse"), format(" - ", CardSeparator::Custom(";;".to_string())));
+    }
+
+    #[test]
+    fn load_blank_line_separated_cards() {
+        let text = "Hund\ndog, a pet\n\nKatze\ncat\n";
+        let deck = load_plain_text(text, &sniff(text), &ColumnMapping::default(), RowPolicy::Blank).unwrap();
+        let cards: Vec<(&str, &str, usize)> = deck.cards.iter().map(|card| (card.front.as_str(), card.back.as_str(), card.row)).collect();
+        assert_eq!(cards, [("Hund", "dog, a pet", 1), ("Katze", "cat", 4)]);
+    }
+
+    #[test]
+    fn load_one_card_per_line() {
+        let text = "Hund\tdog\r\nKatze\tcat\r\nMaus\tmouse\r\n";
+        let deck = load_plain_text(text, &sniff(text), &ColumnMapping::default(), RowPolicy::Blank).unwrap();
+        let cards: Vec<(&str, &str, usize)> = deck.cards.iter().map(|card| (card.front.as_str(), card.back.as_str(), card.row)).collect();
+        assert_eq!(cards, [("Hund", "dog", 1), ("Katze", "cat", 2), ("Maus", "mouse", 3)]);
+    }
+}
@@ -0,0 +1,58 @@
+use crate::deck::{self, ColumnMapping, Deck, RowPolicy};
+use calamine::{open_workbook_auto, Data, DataType, Reader};
+use csv::StringRecord;
+use std::path::Path;
+
+/// The file extensions Facade opens as spreadsheets.
+pub const EXTENSIONS: [&str; 5] = ["xlsx", "xlsm", "xlsb", "xls", "ods"];
+
+pub fn is_spreadsheet(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default();
+    EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))
+}
+
+pub fn sheet_names(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(open_workbook_auto(path)?.sheet_names())
+}
+
+/// Reads a worksheet as rows of text, each numbered as it is in the spreadsheet
+/// (starting at 1). Empty rows are left out.
+pub fn read_sheet(path: &Path, sheet: &str) -> Result<Vec<(usize, StringRecord)>, Box<dyn std::error::Error>> {
+    let mut workbook = open_workbook_auto(path)?;
+    let range = workbook.worksheet_range(sheet)?;
+    let (first_row, first_column) = range.start().map_or((0, 0), |(row, column)| (row as usize, column as usize));
+
+    // Pad the rows so column indexes match the spreadsheet's columns (A is 0)
+    // even if the used range doesn't start at column A.
+    Ok(range.rows()
+        .enumerate()
+        .filter(|(_, cells)| cells.iter().any(|cell| !cell.is_empty()))
+        .map(|(index, cells)| {
+            let record: StringRecord = std::iter::repeat_n(String::new(), first_column)
+                .chain(cells.iter().map(cell_text))
+                .collect();
+            (first_row + index + 1, record)
+        })
+        .collect())
+}
+
+fn cell_text(cell: &Data) -> String {
+    match cell {
+        // Dates are stored as day counts, print them as dates instead
+        Data::DateTime(_) => match cell.as_datetime() {
+            Some(datetime) => datetime.to_string().trim_end_matches(" 00:00:00").to_string(),
+            None => cell.to_string(),
+        },
+        _ => cell.to_string(),
+    }
+}
+
+/// Loads a deck from a worksheet. Rows above `first_row` are ignored, and if
+/// `has_headers` is set the first row that is read holds the column names.
+pub fn load_spreadsheet(path: &Path, sheet: &str, first_row: usize, has_headers: bool, mapping: &ColumnMapping, policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+    let mut rows = read_sheet(path, sheet)?
+        .into_iter()
+        .filter(|(row, _)| *row >= first_row);
+    let headers = if has_headers { rows.next().map(|(_, record)| record) } else { None };
+    deck::cards_from_records(headers.as_ref(), rows.map(Ok), mapping, policy, true)
+}
@@ -0,0 +1,287 @@
+use crate::deck::{self, Card, CardStyle, ColumnMapping, Deck, RowPolicy};
+use crate::hyphenation;
+use csv::StringRecord;
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::path::Path;
+
+/// The file extensions of structured decks.
+pub const EXTENSIONS: [&str; 4] = ["json", "yaml", "yml", "toml"];
+
+pub fn is_structured(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default();
+    EXTENSIONS.iter().any(|ext| extension.eq_ignore_ascii_case(ext))
+}
+
+/// A deck written as JSON, YAML or TOML, for example:
+///
+/// ```yaml
+/// cards:
+///   - id: hund
+///     count: 2
+///     fields: { Term: Hund, Definition: dog }
+///     tags: [animals]
+///     style: { back_size: 16 }
+///     faces:
+///       - { front: der Hund, back: "plural: die Hunde" }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeckFile {
+    cards: Vec<CardEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CardEntry {
+    /// Printed on the card if IDs are turned on. Derived from the text if missing.
+    id: Option<String>,
+    /// How many copies of the card and its faces are printed.
+    count: Option<usize>,
+    /// Named fields, mapped to the sides like the columns of a CSV file.
+    fields: IndexMap<String, FieldValue>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    style: CardStyle,
+    /// Extra cards printed right after this one, with the same tags, style and count.
+    #[serde(default)]
+    faces: Vec<Face>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Face {
+    front: String,
+    #[serde(default)]
+    back: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FieldValue {
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl FieldValue {
+    fn to_text(&self) -> String {
+        match self {
+            FieldValue::Text(text) => text.clone(),
+            FieldValue::Integer(number) => number.to_string(),
+            FieldValue::Float(number) => number.to_string(),
+            FieldValue::Bool(value) => value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format, Box<dyn std::error::Error>> {
+        let extension = path.extension().unwrap_or_default().to_ascii_lowercase();
+        match extension.to_str() {
+            Some("json") => Ok(Format::Json),
+            Some("yaml" | "yml") => Ok(Format::Yaml),
+            Some("toml") => Ok(Format::Toml),
+            _ => Err(format!("{} is not a JSON, YAML or TOML file", path.display()).into()),
+        }
+    }
+}
+
+fn parse(text: &str, format: Format) -> Result<DeckFile, Box<dyn std::error::Error>> {
+    // The parsers' messages already name the line and column of the problem
+    match format {
+        Format::Json => serde_json::from_str(text).map_err(|e| format!("Invalid JSON deck: {}", e).into()),
+        Format::Yaml => serde_yaml::from_str(text).map_err(|e| format!("Invalid YAML deck: {}", e).into()),
+        Format::Toml => toml::from_str(text).map_err(|e| format!("Invalid TOML deck: {}", e).into()),
+    }
+}
+
+/// The field names used by the cards of the deck, in order of first appearance.
+/// They play the part of a CSV header row in the column mapping.
+pub fn field_names(path: &Path) -> Result<StringRecord, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(headers(&parse(&text, Format::of(path)?)?))
+}
+
+fn headers(deck_file: &DeckFile) -> StringRecord {
+    let mut names: Vec<&str> = Vec::new();
+    for name in deck_file.cards.iter().flat_map(|card| card.fields.keys()) {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    StringRecord::from(names)
+}
+
+/// Loads a structured deck. Each card's fields are mapped to the sides with
+/// `mapping`, and its extra faces follow it as cards of their own, with the
+/// card's ID numbered `-2`, `-3`, and so on.
+pub fn load_structured(path: &Path, mapping: &ColumnMapping, policy: RowPolicy) -> Result<Deck, Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let format = Format::of(path)?;
+    let deck_file = parse(&text, format)?;
+    let headers = headers(&deck_file);
+    let lines = card_lines(&text, format);
+    // Fall back to the card's position if its line couldn't be found
+    let line = |index: usize| lines.get(index).copied().unwrap_or(index + 1);
+
+    let records = deck_file.cards.iter().enumerate().map(|(index, card)| {
+        let record: StringRecord = headers.iter()
+            .map(|name| card.fields.get(name).map(FieldValue::to_text).unwrap_or_default())
+            .collect();
+        Ok((index, record))
+    });
+    let mapped = deck::cards_from_records(Some(&headers), records, mapping, policy, false)?;
+
+    let mut deck = Deck {
+        cards: Vec::new(),
+        diagnostics: mapped.diagnostics.into_iter()
+            .map(|diagnostic| deck::Diagnostic { row: line(diagnostic.row), ..diagnostic })
+            .collect(),
+    };
+    for card in mapped.cards {
+        let entry = &deck_file.cards[card.row];
+        let row = line(card.row);
+        check_style(&entry.style).map_err(|message| deck::Diagnostic::new(row, message).to_string())?;
+        let count = entry.count.map(|count| deck::check_quantity(count).unwrap_or_else(|message| {
+            deck.diagnostics.push(deck::Diagnostic::new(row, message));
+            deck::MAX_QUANTITY
+        }));
+        let card = Card {
+            row,
+            id: entry.id.clone().unwrap_or(card.id),
+            tags: entry.tags.iter().cloned().chain(card.tags).collect(),
+            style: entry.style.clone(),
+            quantity: count.or(card.quantity),
+            ..card
+        };
+        let faces: Vec<Card> = entry.faces.iter().enumerate().map(|(number, face)| Card {
+            front: face.front.clone(),
+            back: face.back.clone(),
+            row,
+            id: if card.id.is_empty() { String::new() } else { format!("{}-{}", card.id, number + 2) },
+            tags: card.tags.clone(),
+            style: entry.style.clone(),
+            quantity: card.quantity,
+            ..Card::default()
+        }).collect();
+        deck.cards.push(card);
+        deck.cards.extend(faces);
+    }
+    Ok(deck)
+}
+
+fn check_style(style: &CardStyle) -> Result<(), String> {
+    if [style.front_size, style.back_size].into_iter().flatten().any(|size| !(size > 0.0 && size.is_finite())) {
+        return Err("font sizes must be positive numbers".to_string());
+    }
+    // The font name ends up in an SVG style attribute
+    if let Some(font) = &style.font
+        && font.contains(['"', '\'', ';', '<', '>', '&'])
+    {
+        return Err(format!("\"{}\" is not a valid font name", font));
+    }
+    if let Some(language) = &style.language
+        && hyphenation::language(language).is_none()
+    {
+        return Err(format!("there are no hyphenation patterns for the language \"{}\"", language));
+    }
+    Ok(())
+}
+
+/// Finds the line (starting at 1) each entry of the top-level `cards` list starts on,
+/// so problems with a card can point to it. Only the usual layouts are recognised:
+/// `[[cards]]` tables in TOML and block sequences in YAML.
+fn card_lines(text: &str, format: Format) -> Vec<usize> {
+    match format {
+        Format::Json => json_card_lines(text),
+        Format::Yaml => yaml_card_lines(text),
+        Format::Toml => text.lines()
+            .enumerate()
+            .filter(|(_, line)| line.split('#').next().unwrap_or_default().replace(char::is_whitespace, "") == "[[cards]]")
+            .map(|(index, _)| index + 1)
+            .collect(),
+    }
+}
+
+fn json_card_lines(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut line = 1;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut string = String::new();
+    let mut last_string = String::new();
+    let mut key = String::new();
+    let mut in_cards = false;
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+        }
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                last_string = std::mem::take(&mut string);
+                continue;
+            }
+            string.push(c);
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            ':' if depth == 1 => key = last_string.clone(),
+            '{' | '[' => {
+                if c == '[' && depth == 1 {
+                    in_cards = key == "cards";
+                } else if c == '{' && depth == 2 && in_cards {
+                    lines.push(line);
+                }
+                depth += 1;
+            }
+            '}' | ']' => depth -= 1,
+            _ => {}
+        }
+    }
+    lines
+}
+
+fn yaml_card_lines(text: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut in_cards = false;
+    let mut item_indent: Option<usize> = None;
+    for (index, line) in text.lines().enumerate() {
+        let content = line.trim_start();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - content.len();
+        let is_item = content == "-" || content.starts_with("- ");
+        if !in_cards {
+            in_cards = indent == 0 && content.trim_end() == "cards:";
+            continue;
+        }
+        match item_indent {
+            None if is_item => item_indent = Some(indent),
+            None => break,
+            Some(item_indent) if indent < item_indent || (indent == item_indent && !is_item) => break,
+            _ => {}
+        }
+        if is_item && Some(indent) == item_indent {
+            lines.push(index + 1);
+        }
+    }
+    lines
+}
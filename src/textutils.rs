@@ -1,4 +1,7 @@
-use crate::textutils;
+use crate::entities;
+use crate::fonts::{self, FontMetrics, TextStyle};
+use unicode_linebreak::BreakClass;
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug, PartialEq,Clone)]
 pub enum HtmlToken {
@@ -6,70 +9,197 @@ pub enum HtmlToken {
     HtmlTag {
         name: String,
         is_closing: bool,
+        /// The attributes of an opening tag as name and value, with the names in lowercase.
+        attributes: Vec<(String, String)>,
     },
     Space,
+    /// A `<br>`, which always ends the line.
+    LineBreak,
 }
 
-pub fn tokenize_html(input: &str) -> Vec<HtmlToken> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+impl HtmlToken {
+    /// The tag that closes this opening tag.
+    fn closing(&self) -> HtmlToken {
+        match self {
+            HtmlToken::HtmlTag { name, .. } => HtmlToken::HtmlTag { name: name.clone(), is_closing: true, attributes: Vec::new() },
+            token => token.clone(),
+        }
+    }
 
-    while let Some(current) = chars.next() {
-        match current {
-            // Handle potential HTML tags
-            '<' => {
-                let mut tag = String::new();
-                let mut is_closing = false;
-
-                // Check for different closing tag variations
-                if let Some(&next) = chars.peek() {
-                    is_closing = match next {
-                        '/' | '\\' => true,
-                        _ => false
-                    };
-
-                    // Consume the closing indicator if present
-                    if is_closing {
-                        chars.next();
-                    }
-                }
+    fn tag_name(&self) -> &str {
+        match self {
+            HtmlToken::HtmlTag { name, .. } => name,
+            _ => "",
+        }
+    }
+}
 
-                // Collect tag name
-                while let Some(&c) = chars.peek() {
-                    // Stop collecting tag name at closing markers
-                    if c == '>' || c == '/' || c == '\\' {
-                        chars.next(); // consume the closing marker
-
-                        // Check for potential self-closing tag with />
-                        if c == '/' {
-                            if let Some(&next) = chars.peek() {
-                                if next == '>' {
-                                    chars.next(); // consume '>'
-                                }
-                            }
-                        }
-                        break;
-                    }
+/// Whether a line may break at a whitespace character. No-break spaces keep words together.
+fn is_breaking_space(c: char) -> bool {
+    c.is_whitespace() && !matches!(c, '\u{a0}' | '\u{2007}' | '\u{202f}')
+}
 
-                    // Skip whitespace in tag
-                    if c.is_whitespace() {
-                        chars.next();
-                        continue;
-                    }
+/// Splits text without spaces where the Unicode line breaking algorithm (UAX #14)
+/// allows a line to end, such as after a hyphen or between two ideographs.
+fn line_break_pieces(word: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (index, _) in unicode_linebreak::linebreaks(word) {
+        if index > start && index < word.len() {
+            pieces.push(&word[start..index]);
+            start = index;
+        }
+    }
+    pieces.push(&word[start..]);
+    pieces
+}
 
-                    tag.push(chars.next().unwrap());
-                }
+/// How formatting is written in the text of a deck.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Markup {
+    /// Tags such as `<b>bold</b>`.
+    #[default]
+    Html,
+    /// Markers such as `**bold**`, read by `tokenize_markdown`.
+    Markdown,
+    /// No formatting at all: the text is printed as it is.
+    Plain,
+}
 
-                if !tag.is_empty() {
-                    tokens.push(HtmlToken::HtmlTag {
-                        name: tag,
-                        is_closing,
-                    });
-                }
+/// The Markdown-style markers and the tags they stand for. The doubled markers
+/// come first, so `**` isn't read as two `*`.
+const MARKDOWN_MARKERS: [(&str, &str); 6] = [("**", "b"), ("__", "u"), ("~~", "s"), ("*", "i"), ("^", "sup"), ("~", "sub")];
+
+/// Splits card text written with `markup` into words, spaces and tags.
+pub fn tokenize(text: &str, markup: Markup) -> Vec<HtmlToken> {
+    match markup {
+        Markup::Html => tokenize_html(text),
+        Markup::Markdown => tokenize_markdown(text),
+        Markup::Plain => text_tokens(text),
+    }
+}
+
+/// Rewrites card text written with `markup` as the tags `tokenize_html` reads,
+/// so it wraps and draws the same whichever way it was written.
+pub fn to_html(text: &str, markup: Markup) -> String {
+    match markup {
+        Markup::Html => text.to_string(),
+        _ => html_tokens_to_string(tokenize(text, markup)),
+    }
+}
+
+/// Splits text without any markup into words and spaces.
+fn text_tokens(text: &str) -> Vec<HtmlToken> {
+    let mut tokens = Vec::new();
+    for (index, word) in text.split(is_breaking_space).enumerate() {
+        if index > 0 {
+            tokens.push(HtmlToken::Space);
+        }
+        if !word.is_empty() {
+            tokens.extend(line_break_pieces(word).into_iter().map(|piece| HtmlToken::Word(piece.to_string())));
+        }
+    }
+    tokens
+}
+
+/// Splits text formatted with `**bold**`, `*italic*`, `__underline__`, `~~strike~~`,
+/// `^superscript^` and `~subscript~` into the same tokens as the tags they stand for.
+/// A backslash escapes the next character, markers without a partner are kept
+/// as they are, and everything else is text, `<` and `&` included.
+pub fn tokenize_markdown(text: &str) -> Vec<HtmlToken> {
+    let mut tokens = Vec::new();
+    let mut plain = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '\\'
+            && let Some(escaped) = rest[1..].chars().next()
+            && escaped.is_ascii_punctuation()
+        {
+            plain.push(escaped);
+            rest = &rest[1 + escaped.len_utf8()..];
+            continue;
+        }
+        let emphasis = MARKDOWN_MARKERS.into_iter().find_map(|(marker, tag)| {
+            let inner = rest.strip_prefix(marker)?;
+            let end = closing_marker(inner, marker)?;
+            Some((marker, tag, &inner[..end]))
+        });
+        match emphasis {
+            Some((marker, tag, inner)) => {
+                tokens.extend(text_tokens(&std::mem::take(&mut plain)));
+                let open = HtmlToken::HtmlTag { name: tag.to_string(), is_closing: false, attributes: Vec::new() };
+                let close = open.closing();
+                tokens.push(open);
+                tokens.extend(tokenize_markdown(inner));
+                tokens.push(close);
+                rest = &rest[marker.len() * 2 + inner.len()..];
+            }
+            None => {
+                plain.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    tokens.extend(text_tokens(&plain));
+    tokens
+}
+
+/// Finds the marker that closes emphasis, skipping escaped characters.
+/// Emphasis can't be empty or start with a space, and a lone `*` or `~`
+/// doesn't close on a `**` or `~~`.
+fn closing_marker(text: &str, marker: &str) -> Option<usize> {
+    if text.starts_with(char::is_whitespace) || text.starts_with(marker) {
+        return None;
+    }
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+            continue;
+        }
+        if index > 0 && text[index..].starts_with(marker) {
+            let double = marker.repeat(2);
+            if marker.len() == 1 && text[index..].starts_with(&double) {
+                // Skip over a nested run of the doubled marker
+                let inner = &text[index + 2..];
+                let end = closing_marker(inner, &double)?;
+                let skip = index + 2 + end + 2;
+                while chars.next().is_some_and(|(i, _)| i + 1 < skip) {}
+                continue;
             }
+            if !text[..index].ends_with(char::is_whitespace) {
+                return Some(index);
+            }
+        }
+    }
+    None
+}
 
+/// Splits card text into words, spaces and tags. Character references such as
+/// `&amp;` are decoded, and a `<` that doesn't start a tag is kept as text.
+pub fn tokenize_html(input: &str) -> Vec<HtmlToken> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(current) = chars.next() {
+        // Handle HTML tags
+        if current == '<'
+            && let Some((token, text, length)) = tag_at(chars.clone())
+        {
+            chars.nth(length - 1);
+            // A self-closing tag such as `<b/>` has nothing in it
+            let is_empty = text.ends_with('/') && matches!(token, HtmlToken::HtmlTag { is_closing: false, .. });
+            let closing = token.closing();
+            tokens.push(token);
+            if is_empty {
+                tokens.push(closing);
+            }
+            continue;
+        }
+
+        match current {
             // Handle whitespace
-            c if c.is_whitespace() => {
+            c if is_breaking_space(c) => {
                 tokens.push(HtmlToken::Space);
             }
 
@@ -79,113 +209,331 @@ pub fn tokenize_html(input: &str) -> Vec<HtmlToken> {
 
                 // Collect consecutive non-whitespace, non-tag characters
                 while let Some(&next) = chars.peek() {
-                    if next.is_whitespace() || next == '<' {
+                    if is_breaking_space(next) || (next == '<' && tag_at(chars.clone().skip(1)).is_some()) {
                         break;
                     }
                     word.push(chars.next().unwrap());
                 }
+                let word = entities::decode(&word);
 
-                tokens.push(HtmlToken::Word(word));
+                // Words without spaces may still break, as CJK text does between characters
+                tokens.extend(line_break_pieces(&word).into_iter().map(|piece| HtmlToken::Word(piece.to_string())));
             }
         }
     }
 
-    tokens
+    balance_tags(tokens)
 }
-pub fn wrap_html_tokens(tokens: &[HtmlToken], max_line_length: usize) -> Option<Vec<Vec<HtmlToken>>> {
-    let mut tokens = Vec::from(tokens);
-    let mut lines: Vec<Vec<HtmlToken>> = Vec::new();
-    let mut open_tags: Vec<HtmlToken> = Vec::new();
 
-    fn token_length(token: &HtmlToken) -> usize {
-        match token {
-            HtmlToken::Word(word) => word.len(),
-            HtmlToken::Space => 1,
-            HtmlToken::HtmlTag { .. } => 0,
+/// The tag that starts right after a `<`, with its text up to the `>` and the
+/// number of characters it takes up, the `>` included. `None` if the `<` is a
+/// less-than sign instead, as in `x < y` or `a<b && c>d`.
+fn tag_at(rest: impl Iterator<Item = char>) -> Option<(HtmlToken, String, usize)> {
+    let mut text = String::new();
+    let mut quote = None;
+    for (index, c) in rest.enumerate() {
+        // The end of the tag may be inside an attribute value
+        match quote {
+            Some(open) if c == open => quote = None,
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '>' => {
+                let token = parse_tag(&text)?;
+                return Some((token, text, index + 1));
+            }
+            _ => {}
         }
+        text.push(c);
     }
-    while tokens.len() > 0 && tokens.iter().any(|t| if let HtmlToken::Word(_) = t {true} else {false}) {
-        let mut current_line: Vec<HtmlToken> = Vec::new();
-        let mut current_line_length = 0;
-        for tag in open_tags.iter().rev() {
-            if let HtmlToken::HtmlTag {name, is_closing: _is_closing} = tag {
-                current_line.push(HtmlToken::HtmlTag {name: name.clone(), is_closing: false});
-            }
+    None
+}
+
+/// Parses what is between the `<` and `>` of a tag, or returns `None` if it isn't
+/// a well-formed tag. `<\\b>` closes a tag like `</b>` does.
+fn parse_tag(tag: &str) -> Option<HtmlToken> {
+    let (is_closing, tag) = match tag.strip_prefix(['/', '\\']) {
+        Some(rest) => (true, rest),
+        None => (false, tag),
+    };
+    let tag = tag.strip_suffix('/').unwrap_or(tag).trim_end();
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let name = &tag[..name_end];
+    let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !is_name {
+        return None;
+    }
+    if name.eq_ignore_ascii_case("br") {
+        return Some(HtmlToken::LineBreak);
+    }
+    let attributes = parse_attributes(&tag[name_end..])?;
+    if is_closing && !attributes.is_empty() {
+        return None;
+    }
+    Some(HtmlToken::HtmlTag { name: name.to_string(), is_closing, attributes })
+}
+
+/// Parses `name="value"`, `name='value'`, `name=value` and bare `name` attributes,
+/// or returns `None` if there is something else among them.
+fn parse_attributes(text: &str) -> Option<Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_:.".contains(c));
+        if !is_name {
+            return None;
         }
-        while current_line_length < max_line_length && tokens.len() > 0 {
-            if let HtmlToken::HtmlTag {name, is_closing } = tokens[0].clone() {
-                //If we don't have enough room for the next word, don't insert this tag
-                if let Some(HtmlToken::Word(word)) = tokens.iter().find(|x| if let HtmlToken::Word(_) = x { true } else { false }) {
-                    if word.len() > (max_line_length - current_line_length) {
-                        break;
-                    } else {
-                        if !is_closing {
-                            current_line.push(tokens[0].clone());
-                            open_tags.push(tokens[0].clone());
-                            tokens.remove(0);
-                        } else {
-                            if let Some(tag) = open_tags.pop() {
-                                if let HtmlToken::HtmlTag {name: name_2, is_closing: _} = tag {
-                                    if name == name_2{
-                                        current_line.push(tokens[0].clone());
-                                        tokens.remove(0);
-                                    }else{
-                                        return None;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    break;
+        rest = rest[name_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (found, remainder) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let inner = &after[1..];
+                    let end = inner.find(quote)?;
+                    (&inner[..end], &inner[end + 1..])
                 }
-            } else if tokens[0] == HtmlToken::Space {
-                //If we don't have enough room for the next word, delete the space
-                if let Some(HtmlToken::Word(word)) = tokens.iter().find(|x| if let HtmlToken::Word(_) = x { true } else { false }) {
-                    if word.len() + 1 > (max_line_length - current_line_length) {
-                        tokens.remove(0);
-                        break;
-                    } else {
-                        current_line.push(tokens[0].clone());
-                        tokens.remove(0);
+                _ => {
+                    let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            value = entities::decode(found);
+            rest = remainder;
+        }
+        attributes.push((name, value));
+        rest = rest.trim_start();
+    }
+    Some(attributes)
+}
+
+/// Makes every closing tag match the tag opened last. A closing tag for a tag
+/// opened earlier also closes the tags opened after it, which are opened again
+/// right after it; closing tags that match no open tag are dropped, and the tags
+/// still open at the end are closed there.
+fn balance_tags(tokens: Vec<HtmlToken>) -> Vec<HtmlToken> {
+    let mut balanced = Vec::with_capacity(tokens.len());
+    let mut open_tags: Vec<HtmlToken> = Vec::new();
+    for token in tokens {
+        match token {
+            HtmlToken::HtmlTag { ref name, is_closing: true, .. } => {
+                let Some(position) = open_tags.iter().rposition(|open| open.tag_name().eq_ignore_ascii_case(name)) else {
+                    continue;
+                };
+                let reopened = open_tags.split_off(position + 1);
+                for open in reopened.iter().rev().chain([&open_tags[position]]) {
+                    balanced.push(open.closing());
+                }
+                open_tags.pop();
+                balanced.extend(reopened.iter().cloned());
+                open_tags.extend(reopened);
+            }
+            HtmlToken::HtmlTag { is_closing: false, .. } => {
+                open_tags.push(token.clone());
+                balanced.push(token);
+            }
+            token => balanced.push(token),
+        }
+    }
+    balanced.extend(open_tags.iter().rev().map(HtmlToken::closing));
+    balanced
+}
+
+/// How the tags that are open change the width of the text.
+fn style_of(open_tags: &[HtmlToken]) -> TextStyle {
+    let is_open = |names: &[&str]| open_tags.iter().any(|tag| names.iter().any(|name| tag.tag_name().eq_ignore_ascii_case(name)));
+    let scale: f64 = open_tags.iter().map(|tag| tag_style(tag).scale).product();
+    TextStyle {
+        bold: is_open(&["b", "strong"]),
+        italic: is_open(&["i", "em"]),
+        size_percent: (scale * 100.0).round() as u16,
+    }
+}
+
+/// Applies a tag to the list of open tags. Returns false for a closing tag
+/// that doesn't match the tag opened last.
+fn update_open_tags(open_tags: &mut Vec<HtmlToken>, token: &HtmlToken) -> bool {
+    match token {
+        HtmlToken::HtmlTag { is_closing: false, .. } => open_tags.push(token.clone()),
+        HtmlToken::HtmlTag { name, is_closing: true, .. } => match open_tags.pop() {
+            Some(open) if open.tag_name() != name => return false,
+            _ => {}
+        },
+        _ => {}
+    }
+    true
+}
+
+/// The place before a word where a line may break.
+struct BreakPoint {
+    /// The position in the line of the spaces and tags in front of the word.
+    index: usize,
+    open_tags: Vec<HtmlToken>,
+    /// The width of the line before the spaces.
+    width_before: f64,
+    spaces_width: f64,
+    /// Whether there are words before it on the line, so breaking there leaves some behind.
+    after_words: bool,
+}
+
+/// Breaks the tokens into lines no wider than `max_width`, measured by `width`
+/// with the style of the tags around each word. Tags still open at the end of
+/// a line are closed there and opened again on the next line. A line break ends
+/// the line even if it isn't full, and stays at its end so joined lines keep it. Returns `None` if the tags aren't nested properly.
+pub fn wrap_html_tokens(tokens: &[HtmlToken], max_width: f64, width: &dyn Fn(&str, TextStyle) -> f64) -> Option<Vec<Vec<HtmlToken>>> {
+    let mut lines: Vec<Vec<HtmlToken>> = Vec::new();
+    let mut open_tags: Vec<HtmlToken> = Vec::new();
+    let mut line: Vec<HtmlToken> = Vec::new();
+    let mut line_width = 0.0;
+    let mut line_has_words = false;
+    // The spaces and tags since the last word, which go wherever the next word goes
+    let mut pending: Vec<HtmlToken> = Vec::new();
+    let mut break_point = BreakPoint { index: 0, open_tags: Vec::new(), width_before: 0.0, spaces_width: 0.0, after_words: false };
+
+    let close_line = |line: &mut Vec<HtmlToken>, open_tags: &[HtmlToken]| {
+        line.extend(open_tags.iter().rev().map(HtmlToken::closing));
+    };
+
+    for token in tokens {
+        let word = match token {
+            HtmlToken::Word(word) => word,
+            HtmlToken::LineBreak => {
+                // Tags before the break belong to this line, spaces are dropped
+                for pending_token in pending.drain(..).filter(|token| *token != HtmlToken::Space) {
+                    if !update_open_tags(&mut open_tags, &pending_token) {
+                        return None;
                     }
-                } else {
-                    break;
+                    line.push(pending_token);
                 }
+                line.push(HtmlToken::LineBreak);
+                close_line(&mut line, &open_tags);
+                lines.push(std::mem::take(&mut line));
+                line.extend(open_tags.iter().cloned());
+                line_width = 0.0;
+                line_has_words = false;
+                continue;
+            }
+            _ => {
+                pending.push(token.clone());
+                continue;
+            }
+        };
+        // Spaces at the start of a line are dropped
+        if !line_has_words {
+            pending.retain(|token| *token != HtmlToken::Space);
+        }
+        // Words with only tags between them, like H<sub>2</sub>O, are kept together
+        let glued = line_has_words && !pending.is_empty() && !pending.contains(&HtmlToken::Space);
+        // Tags closed right after the last word end with it, wherever this word goes
+        let closed = pending.iter()
+            .take_while(|token| matches!(token, HtmlToken::HtmlTag { is_closing: true, .. } | HtmlToken::Space))
+            .count();
+        let mut spaces = Vec::new();
+        for pending_token in pending.drain(..closed) {
+            if pending_token == HtmlToken::Space {
+                spaces.push(pending_token);
+            } else if !update_open_tags(&mut open_tags, &pending_token) {
+                return None;
             } else {
-                current_line.push(tokens[0].clone());
-                current_line_length += token_length(&tokens[0]);
-                tokens.remove(0);
+                line.push(pending_token);
             }
         }
-        for tag in open_tags.iter().rev() {
-            if let HtmlToken::HtmlTag {name, is_closing} = tag {
-                if !is_closing {
-                    current_line.push(HtmlToken::HtmlTag {name: name.clone(), is_closing: true});
-                }
+        pending.splice(0..0, spaces);
+        // Measure the spaces and the word with the style they are set in
+        let mut unit_tags = open_tags.clone();
+        let mut spaces_width = 0.0;
+        for pending_token in &pending {
+            if *pending_token == HtmlToken::Space {
+                spaces_width += width(" ", style_of(&unit_tags));
+            }
+            update_open_tags(&mut unit_tags, pending_token);
+        }
+        let word_width = width(word, style_of(&unit_tags));
+
+        if line_has_words && line_width + spaces_width + word_width > max_width {
+            if !glued {
+                close_line(&mut line, &open_tags);
+                lines.push(std::mem::take(&mut line));
+                line.extend(open_tags.iter().cloned());
+                line_width = 0.0;
+                pending.retain(|token| *token != HtmlToken::Space);
+                spaces_width = 0.0;
+            } else if break_point.after_words {
+                // Move the words this one is glued to onto the next line with it
+                let mut tail = line.split_off(break_point.index);
+                close_line(&mut line, &break_point.open_tags);
+                lines.push(std::mem::take(&mut line));
+                line.extend(break_point.open_tags.iter().cloned());
+                let first_word = tail.iter().position(|token| matches!(token, HtmlToken::Word(_))).unwrap_or(tail.len());
+                let mut index = 0;
+                tail.retain(|token| {
+                    index += 1;
+                    index > first_word || *token != HtmlToken::Space
+                });
+                line.extend(tail);
+                line_width -= break_point.width_before + break_point.spaces_width;
+                break_point.index = break_point.open_tags.len();
+                break_point.after_words = false;
+            }
+        }
+        if !glued {
+            break_point = BreakPoint {
+                index: line.len(),
+                open_tags: open_tags.clone(),
+                width_before: line_width,
+                spaces_width,
+                after_words: line_has_words,
+            };
+        }
+        for pending_token in pending.drain(..) {
+            if !update_open_tags(&mut open_tags, &pending_token) {
+                return None;
             }
+            line.push(pending_token);
         }
-        lines.push(current_line);
+        line.push(token.clone());
+        line_width += spaces_width + word_width;
+        line_has_words = true;
     }
 
+    if line_has_words {
+        for pending_token in pending {
+            if !update_open_tags(&mut open_tags, &pending_token) {
+                return None;
+            }
+            line.push(pending_token);
+        }
+        close_line(&mut line, &open_tags);
+        lines.push(line);
+    }
     Some(lines)
 }
 
 pub fn html_tokens_to_string(tokens: Vec<HtmlToken>) -> String {
     let mut tokens = tokens.clone();
     let mut output = String::new();
-    while tokens.len() > 0 {
+    while !tokens.is_empty() {
         if let HtmlToken::Word(word) = tokens[0].clone() {
-            output.push_str(&word);
+            // Escaped, so the output reads back as the same words
+            output.push_str(&escape_xml(&word));
             tokens.remove(0);
         } else if tokens[0] == HtmlToken::Space {
             output.push(' ');
             tokens.remove(0);
-        } else if let HtmlToken::HtmlTag {name, is_closing} = tokens[0].clone() {
+        } else if tokens[0] == HtmlToken::LineBreak {
+            output.push_str("<br>");
+            tokens.remove(0);
+        } else if let HtmlToken::HtmlTag {name, is_closing, attributes} = tokens[0].clone() {
             if is_closing {
                 output.push_str(&format!("</{}>", name));
             }else {
-                output.push_str(&format!("<{}>", name));
+                output.push('<');
+                output.push_str(&name);
+                for (attribute, value) in attributes {
+                    output.push_str(&format!(" {}=\"{}\"", attribute, escape_xml(&value).replace('"', "&quot;")));
+                }
+                output.push('>');
             }
             tokens.remove(0);
         }
@@ -193,60 +541,489 @@ pub fn html_tokens_to_string(tokens: Vec<HtmlToken>) -> String {
     output
 }
 
-pub fn hyphenate(html_tokens: &mut Vec<HtmlToken>, max_length: usize){
-    for (index, token) in html_tokens.clone().iter().enumerate() {
-        if let HtmlToken::Word(word) = token {
-            if word.len() > max_length {
-                let hyphenated = hyphenate_word(word, max_length);
-                html_tokens.splice(index..index+1, hyphenated.into_iter().map(|x| HtmlToken::Word(x)).collect::<Vec<HtmlToken>>());
+/// Splits the words wider than `max_width` into pieces that fit, each ending in a
+/// hyphen, between syllables if the hyphenation patterns of `language` are given.
+pub fn hyphenate(html_tokens: &mut Vec<HtmlToken>, max_width: f64, width: &dyn Fn(&str, TextStyle) -> f64, language: Option<hypher::Lang>) {
+    *html_tokens = hyphenate_marked(std::mem::take(html_tokens), max_width, width, language)
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect();
+}
+
+/// Hyphenates the tokens like `hyphenate`, marking each piece split from the
+/// next one with its text before the hyphen was added.
+fn hyphenate_marked(
+    html_tokens: Vec<HtmlToken>,
+    max_width: f64,
+    width: &dyn Fn(&str, TextStyle) -> f64,
+    language: Option<hypher::Lang>,
+) -> Vec<(HtmlToken, Option<String>)> {
+    let mut open_tags: Vec<HtmlToken> = Vec::new();
+    let mut hyphenated = Vec::with_capacity(html_tokens.len());
+    for token in html_tokens {
+        update_open_tags(&mut open_tags, &token);
+        match token {
+            HtmlToken::Word(word) => {
+                let style = style_of(&open_tags);
+                let pieces = hyphenate_word(&word, max_width, &|text| width(text, style), language);
+                let last = pieces.len() - 1;
+                let mut rest = word.as_str();
+                for (index, piece) in pieces.into_iter().enumerate() {
+                    if index == last {
+                        hyphenated.push((HtmlToken::Word(piece), None));
+                        break;
+                    }
+                    // Scripts split without a hyphen keep the piece as it is
+                    let unhyphenated = if rest.starts_with(&piece) { piece.clone() } else { piece.strip_suffix('-').unwrap_or(&piece).to_string() };
+                    rest = rest.get(unhyphenated.len()..).unwrap_or_default();
+                    hyphenated.push((HtmlToken::Word(piece), Some(unhyphenated)));
+                }
             }
+            token => hyphenated.push((token, None)),
         }
     }
+    hyphenated
 }
-pub fn hyphenate_word(word: &String, max_length: usize) -> Vec<String> {
-    if word.len() <= max_length {
+
+/// Splits a word into pieces no wider than `max_width`, adding a hyphen to all but
+/// the last in scripts that use hyphens. With a `language` the word is split between
+/// syllables, and only syllables too wide for a line on their own are split anywhere.
+pub fn hyphenate_word(word: &str, max_width: f64, width: &dyn Fn(&str) -> f64, language: Option<hypher::Lang>) -> Vec<String> {
+    if width(word) <= max_width {
         return vec![word.to_string()];
     }
+    let Some(language) = language else {
+        return split_graphemes(word, max_width, width);
+    };
 
+    let syllables: Vec<&str> = hypher::hyphenate(word, language).collect();
     let mut hyphenated = Vec::new();
-    let mut start = 0;
+    let mut piece = String::new();
+    for (index, syllable) in syllables.iter().enumerate() {
+        let hyphen = if index + 1 < syllables.len() { hyphen_after(syllable) } else { "" };
+        if width(&format!("{}{}{}", piece, syllable, hyphen)) <= max_width {
+            piece.push_str(syllable);
+            continue;
+        }
+        if !piece.is_empty() {
+            hyphenated.push(format!("{}{}", piece, hyphen_after(&piece)));
+            piece.clear();
+        }
+        if width(&format!("{}{}", syllable, hyphen)) <= max_width {
+            piece.push_str(syllable);
+        } else {
+            let mut pieces = split_graphemes(syllable, max_width, width);
+            piece = pieces.pop().unwrap_or_default();
+            hyphenated.extend(pieces);
+        }
+    }
+    hyphenated.push(piece);
+    hyphenated
+}
+
+/// Splits a word between grapheme clusters into pieces no wider than `max_width`,
+/// adding a hyphen to all but the last. Every piece keeps at least one grapheme
+/// cluster, however narrow the card.
+fn split_graphemes(word: &str, max_width: f64, width: &dyn Fn(&str) -> f64) -> Vec<String> {
+    let mut hyphenated = Vec::new();
+    let mut piece = String::new();
+    for grapheme in word.graphemes(true) {
+        if !piece.is_empty() && width(&format!("{}{}{}", piece, grapheme, hyphen_after(&piece))) > max_width {
+            hyphenated.push(format!("{}{}", piece, hyphen_after(&piece)));
+            piece.clear();
+        }
+        piece.push_str(grapheme);
+    }
+    hyphenated.push(piece);
+    hyphenated
+}
+
+/// The hyphen to add when a word is split after `text`. Chinese, Japanese, Korean
+/// and scripts such as Thai are split without one.
+fn hyphen_after(text: &str) -> &'static str {
+    let Some(last) = text.chars().last() else {
+        return "-";
+    };
+    match unicode_linebreak::break_property(last as u32) {
+        BreakClass::Ideographic
+        | BreakClass::ConditionalJapaneseStarter
+        | BreakClass::ComplexContext
+        | BreakClass::HangulLvSyllable
+        | BreakClass::HangulLvtSyllable
+        | BreakClass::HangulLJamo
+        | BreakClass::HangulVJamo
+        | BreakClass::HangulTJamo => "",
+        _ => "-",
+    }
+}
+
+/// Wraps `text` set at `font_size_pt` into lines no wider than `max_width` millimetres,
+/// hyphenating words that are wider than a line on their own.
+pub fn wrap_text(text: &str, max_width: f64, font_size_pt: f64, metrics: &FontMetrics, language: Option<hypher::Lang>) -> Vec<String> {
+    let width = |text: &str, style: TextStyle| metrics.width(text, font_size_pt, style);
+    let mut tokens = tokenize_html(text);
+    hyphenate(&mut tokens, max_width, &width, language);
+    // Only tags that aren't nested properly stop the wrapping, and tokenize_html balances them
+    wrap_html_tokens(&tokens, max_width, &width)
+        .expect("tokenize_html balances the tags")
+        .into_iter()
+        .map(html_tokens_to_string)
+        .collect()
+}
+
+/// Wraps `text` like `wrap_text` and splits it after every `lines_per_part` lines,
+/// for text continued on other cards. Each part is markup that reads back as its
+/// share of the text: words split only to fit a line are whole again unless a part
+/// ends inside them, and the tags open where a part ends are opened again in the next.
+pub fn split_text(
+    text: &str,
+    max_width: f64,
+    font_size_pt: f64,
+    metrics: &FontMetrics,
+    language: Option<hypher::Lang>,
+    lines_per_part: usize,
+) -> Vec<String> {
+    let width = |text: &str, style: TextStyle| metrics.width(text, font_size_pt, style);
+    let marked = hyphenate_marked(tokenize_html(text), max_width, &width, language);
+    let tokens: Vec<HtmlToken> = marked.iter().map(|(token, _)| token.clone()).collect();
+    // As in wrap_text, the tags are balanced so the wrapping can't fail
+    let lines = wrap_html_tokens(&tokens, max_width, &width).expect("tokenize_html balances the tags");
+
+    // Every line ends after a word or a line break, so parts are counted in those
+    let is_unit = |token: &HtmlToken| matches!(token, HtmlToken::Word(_) | HtmlToken::LineBreak);
+    let mut part_ends = Vec::new();
+    let mut units = 0;
+    for part_lines in lines.chunks(lines_per_part.max(1)) {
+        units += part_lines.iter().flatten().filter(|token| is_unit(token)).count();
+        part_ends.push(units);
+    }
+
+    let mut parts = Vec::new();
+    let mut part: Vec<HtmlToken> = Vec::new();
+    let mut open_tags: Vec<HtmlToken> = Vec::new();
+    let mut units = 0;
+    let mut ending = false;
+    for (token, unhyphenated) in marked {
+        // The tags closed right after the last word of a part still belong to it
+        if ending && !matches!(token, HtmlToken::HtmlTag { is_closing: true, .. }) {
+            part.extend(open_tags.iter().rev().map(HtmlToken::closing));
+            parts.push(html_tokens_to_string(std::mem::take(&mut part)));
+            part.extend(open_tags.iter().cloned());
+            ending = false;
+            if token == HtmlToken::Space {
+                continue;
+            }
+        }
+        update_open_tags(&mut open_tags, &token);
+        if is_unit(&token) {
+            units += 1;
+            ending = part_ends.contains(&units);
+        }
+        match (token, unhyphenated) {
+            (HtmlToken::Word(_), Some(unhyphenated)) if !ending => part.push(HtmlToken::Word(unhyphenated)),
+            (token, _) => part.push(token),
+        }
+    }
+    if parts.is_empty() || part.iter().any(is_unit) {
+        parts.push(html_tokens_to_string(part));
+    }
+    parts
+}
 
-    while start < word.len() {
-        let end = (start + max_length).min(word.len());
-        let segment = if end < word.len() {
-            format!("{}-", &word[start..end])
+/// The largest font size from `min_size` to `max_size` points, in steps of half a
+/// point, at which `text` fits in `max_width` by `max_height` millimetres without
+/// breaking a word. Text that doesn't fit even at `min_size` is set at `min_size`.
+pub fn fit_font_size(
+    text: &str,
+    max_width: f64,
+    max_height: f64,
+    (min_size, max_size): (f64, f64),
+    line_height_factor: f64,
+    metrics: &FontMetrics,
+) -> f64 {
+    let tokens = tokenize_html(text);
+    let fits = |half_points: i64| {
+        let size = half_points as f64 / 2.0;
+        let width = |text: &str, style: TextStyle| metrics.width(text, size, style);
+        let mut hyphenated = tokens.clone();
+        hyphenate(&mut hyphenated, max_width, &width, None);
+        if hyphenated.len() != tokens.len() {
+            return false;
+        }
+        let lines = wrap_html_tokens(&tokens, max_width, &width).map_or(0, |lines| lines.len());
+        lines as f64 * size * fonts::MM_PER_PT * line_height_factor <= max_height
+    };
+
+    let (mut low, mut high) = ((min_size * 2.0).ceil() as i64, (max_size * 2.0).floor() as i64);
+    if high < low || !fits(low) {
+        return min_size;
+    }
+    while low < high {
+        let middle = (low + high + 1) / 2;
+        if fits(middle) {
+            low = middle;
         } else {
-            word[start..end].to_string()
+            high = middle - 1;
+        }
+    }
+    low as f64 / 2.0
+}
+
+/// Escapes text for use between SVG tags, leaving out the characters XML doesn't allow at all.
+pub fn escape_xml(text: &str) -> String {
+    text.replace(|c| !entities::is_xml_char(c), "").replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The tags card text may contain, with the attributes each may have, besides
+/// `<br>`. `sanitize` removes everything else, and drawing ignores it.
+const ALLOWED_TAGS: [(&str, &[&str]); 13] = [
+    ("b", &[]), ("strong", &[]), ("i", &[]), ("em", &[]), ("u", &[]),
+    ("s", &[]), ("strike", &[]), ("del", &[]), ("sup", &[]), ("sub", &[]),
+    ("mark", &[]), ("span", &["style"]), ("font", &["color", "face", "size"]),
+];
+
+/// How a tag changes the look of its text.
+struct TagStyle {
+    /// The SVG attributes of the `tspan` the tag is drawn with.
+    attributes: Vec<String>,
+    /// The size of the text relative to the text around it.
+    scale: f64,
+}
+
+/// The font sizes of `<font size=1>` to `<font size=7>`, relative to the default of 3.
+const HTML_FONT_SIZES: [f64; 7] = [0.625, 0.8125, 1.0, 1.125, 1.5, 2.0, 3.0];
+
+/// How `tag` is drawn. Besides the formatting tags, `<span style>` and `<font>`
+/// may set the text colour, a background highlight, a relative font size and the
+/// font family; anything else about them is ignored.
+fn tag_style(tag: &HtmlToken) -> TagStyle {
+    let mut style = TagStyle { attributes: Vec::new(), scale: 1.0 };
+    let HtmlToken::HtmlTag { name, attributes, .. } = tag else {
+        return style;
+    };
+    let attribute = |wanted: &str| attributes.iter().find(|(name, _)| name == wanted).map(|(_, value)| value.as_str());
+    match name.to_ascii_lowercase().as_str() {
+        "b" | "strong" => style.attributes.push("font-weight=\"bold\"".to_string()),
+        "i" | "em" => style.attributes.push("font-style=\"italic\"".to_string()),
+        "u" => style.attributes.push("text-decoration=\"underline\"".to_string()),
+        "s" | "strike" | "del" => style.attributes.push("text-decoration=\"line-through\"".to_string()),
+        "sup" | "sub" => {
+            let shift = if name.eq_ignore_ascii_case("sup") { "super" } else { "sub" };
+            style.attributes.push(format!("baseline-shift=\"{}\"", shift));
+            style.scale = fonts::SMALL_SCALE;
+        }
+        "mark" => style.attributes.push(highlight("#ffeb3b")),
+        "span" => {
+            for declaration in attribute("style").unwrap_or_default().split(';') {
+                let Some((property, value)) = declaration.split_once(':') else {
+                    continue;
+                };
+                match property.trim().to_ascii_lowercase().as_str() {
+                    "color" => style.attributes.extend(css_color(value).map(|color| format!("fill=\"{}\"", color))),
+                    "background-color" | "background" => style.attributes.extend(css_color(value).map(highlight)),
+                    "font-size" => style.scale = relative_size(value).unwrap_or(style.scale),
+                    "font-family" => style.attributes.extend(font_family(value).map(|family| format!("font-family=\"{}\"", family))),
+                    _ => {}
+                }
+            }
+        }
+        "font" => {
+            style.attributes.extend(attribute("color").and_then(css_color).map(|color| format!("fill=\"{}\"", color)));
+            style.attributes.extend(attribute("face").and_then(font_family).map(|family| format!("font-family=\"{}\"", family)));
+            style.scale = attribute("size").and_then(html_font_size).unwrap_or(1.0);
+        }
+        _ => {}
+    }
+    if style.scale != 1.0 {
+        style.attributes.push(format!("font-size=\"{}%\"", (style.scale * 100.0).round()));
+    }
+    style
+}
+
+/// A thick outline drawn behind the glyphs, like a highlighter.
+fn highlight(color: &str) -> String {
+    format!("stroke=\"{}\" stroke-width=\"0.3em\" stroke-linejoin=\"round\" paint-order=\"stroke\"", color)
+}
+
+/// A CSS colour written as `#rgb`, `#rrggbb`, a name or `rgb(…)`/`hsl(…)`, or
+/// `None` if it is anything else.
+fn css_color(value: &str) -> Option<&str> {
+    let value = value.trim();
+    let is_hex = value.strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()));
+    let is_name = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphabetic());
+    let is_function = value.strip_suffix(')')
+        .and_then(|value| value.split_once('('))
+        .is_some_and(|(function, arguments)| {
+            ["rgb", "rgba", "hsl", "hsla"].iter().any(|name| function.trim().eq_ignore_ascii_case(name))
+                && arguments.chars().all(|c| c.is_ascii_digit() || " .,%".contains(c))
+        });
+    (is_hex || is_name || is_function).then_some(value)
+}
+
+/// A font size relative to the text around it, from `120%`, `1.2em`, `smaller` or
+/// `larger`. Absolute sizes are ignored, as every card sets its own size.
+fn relative_size(value: &str) -> Option<f64> {
+    let value = value.trim().to_ascii_lowercase();
+    let scale = match value.as_str() {
+        "smaller" => 1.0 / 1.2,
+        "larger" => 1.2,
+        _ => match value.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+            None => value.strip_suffix("em")?.trim().parse::<f64>().ok()?,
+        },
+    };
+    // Keep the text readable and on the card
+    (scale.is_finite() && scale > 0.0).then(|| scale.clamp(0.5, 3.0))
+}
+
+/// The font size of `<font size>`, written from 1 to 7 or relative to 3 as `+1` or `-1`.
+fn html_font_size(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let size = match value.strip_prefix(['+', '-']) {
+        Some(difference) => {
+            let difference: i64 = difference.parse().ok()?;
+            if value.starts_with('-') { 3i64.checked_sub(difference)? } else { 3i64.checked_add(difference)? }
+        }
+        None => value.parse().ok()?,
+    };
+    Some(HTML_FONT_SIZES[size.clamp(1, 7) as usize - 1])
+}
+
+/// A list of font families without the quotes around names, or `None` if it
+/// has characters that don't belong in a font name.
+fn font_family(value: &str) -> Option<String> {
+    let family = value.replace(['"', '\''], "");
+    let family = family.trim();
+    let is_valid = !family.is_empty() && family.chars().all(|c| c.is_alphanumeric() || " -_,".contains(c));
+    is_valid.then(|| family.to_string())
+}
+
+/// Whether `value` is one the attribute `name` of `tag` can be drawn with.
+fn is_allowed_value(tag: &str, name: &str, value: &str) -> bool {
+    match (tag, name) {
+        ("font", "color") => css_color(value).is_some(),
+        ("font", "face") => font_family(value).is_some(),
+        ("font", "size") => html_font_size(value).is_some(),
+        _ => true,
+    }
+}
+
+/// Whether `<span style>` may set `property` to `value`.
+fn is_allowed_style(property: &str, value: &str) -> bool {
+    match property.trim().to_ascii_lowercase().as_str() {
+        "color" | "background-color" | "background" => css_color(value).is_some(),
+        "font-size" => relative_size(value).is_some(),
+        "font-family" => font_family(value).is_some(),
+        _ => false,
+    }
+}
+
+/// Removes the tags, attributes and styles that aren't on the allow-list from
+/// `text`, so a deck can do no more than format its text. Returns the cleaned
+/// text and a description of each thing removed; text with nothing to remove
+/// is returned as it is.
+pub fn sanitize(text: &str) -> (String, Vec<String>) {
+    let mut removed: Vec<String> = Vec::new();
+    let mut note = |what: String| {
+        if !removed.contains(&what) {
+            removed.push(what);
+        }
+    };
+    let mut tokens = Vec::new();
+    for token in tokenize_html(text) {
+        let HtmlToken::HtmlTag { name, is_closing, attributes } = token else {
+            tokens.push(token);
+            continue;
+        };
+        let name = name.to_ascii_lowercase();
+        let Some((_, allowed)) = ALLOWED_TAGS.iter().find(|(allowed, _)| *allowed == name) else {
+            if !is_closing {
+                note(format!("<{}>", name));
+            }
+            continue;
         };
-        hyphenated.push(segment);
-        start = end;
+        let mut kept = Vec::new();
+        for (attribute, value) in attributes {
+            if !allowed.contains(&attribute.as_str()) || !is_allowed_value(&name, &attribute, &value) {
+                note(format!("the {} attribute of <{}>", attribute, name));
+            } else if attribute == "style" {
+                let mut declarations = Vec::new();
+                for declaration in value.split(';').map(str::trim).filter(|declaration| !declaration.is_empty()) {
+                    match declaration.split_once(':') {
+                        Some((property, value)) if is_allowed_style(property, value) => declarations.push(declaration),
+                        _ => note(format!("the style \"{}\"", declaration)),
+                    }
+                }
+                if !declarations.is_empty() {
+                    kept.push((attribute, declarations.join("; ")));
+                }
+            } else {
+                kept.push((attribute, value));
+            }
+        }
+        tokens.push(HtmlToken::HtmlTag { name, is_closing, attributes: kept });
+    }
+    if removed.is_empty() {
+        return (text.to_string(), removed);
     }
+    (html_tokens_to_string(tokens), removed)
+}
 
-    hyphenated
+/// Turns a wrapped line into SVG text, drawing its tags as nested `tspan`s.
+fn line_to_svg(line: &str) -> String {
+    let mut svg = String::new();
+    let mut has_words = false;
+    // Whether each open tag was drawn with a tspan
+    let mut open_tspans: Vec<bool> = Vec::new();
+    for token in tokenize_html(line) {
+        match token {
+            HtmlToken::Word(word) => {
+                svg.push_str(&escape_xml(&word));
+                has_words = true;
+            }
+            HtmlToken::Space => svg.push(' '),
+            HtmlToken::LineBreak => {}
+            HtmlToken::HtmlTag { is_closing: false, .. } => {
+                let style = tag_style(&token);
+                if !style.attributes.is_empty() {
+                    svg.push_str(&format!("<tspan {}>", style.attributes.join(" ")));
+                }
+                open_tspans.push(!style.attributes.is_empty());
+            }
+            HtmlToken::HtmlTag { is_closing: true, .. } => {
+                if open_tspans.pop() == Some(true) {
+                    svg.push_str("</tspan>");
+                }
+            }
+        }
+    }
+    // A line without any text would be dropped along with its spacing
+    if !has_words {
+        svg.push('\u{a0}');
+    }
+    svg
 }
 
+/// Sets `text` in lines no wider than `max_width` millimetres, centred on the point given.
+#[allow(clippy::too_many_arguments)]
 pub fn generate_centered_text_element(
     text: &str,
     center_x: f64,
     center_y: f64,
-    max_chars: usize,
+    max_width: f64,
     font_size_pt: f64,
     line_height_factor: f64,
     font_family: &str,
+    metrics: &FontMetrics,
+    language: Option<hypher::Lang>,
 ) -> String {
     // Wrap the text (this needs to account for the tags in the wrapping process)
-    //let wrapped_lines = wrap_text(text, max_chars);
-    let mut wrapped_lines: Vec<String> = Vec::new();
-    let mut tokens = textutils::tokenize_html(text);
-    hyphenate(&mut tokens, max_chars);
-    for line in textutils::wrap_html_tokens(&tokens, max_chars).unwrap() {
-        wrapped_lines.push(html_tokens_to_string(line));
-    }
+    let wrapped_lines = wrap_text(text, max_width, font_size_pt, metrics, language);
     let total_lines = wrapped_lines.len();
 
     // Convert point size to mm for consistent spacing
-    // Approximate conversion: 1pt ≈ 0.35mm
-    let font_size_mm = font_size_pt * 0.35;
+    let font_size_mm = font_size_pt * fonts::MM_PER_PT;
 
     // Calculate line height in mm
     let line_height_mm = font_size_mm * line_height_factor;
@@ -268,70 +1045,170 @@ pub fn generate_centered_text_element(
         };
 
 
-        // Process the line for underline tags
-        if line.contains("<u>") || line.contains("</u>") {
-            // Start the tspan for this line
-            let mut line_tspan = format!("<tspan x=\"{}mm\" dy=\"{}mm\">", center_x, dy);
+        text_element.push_str(&format!("<tspan x=\"{}mm\" dy=\"{}mm\">{}</tspan>\n  ", center_x, dy, line_to_svg(line)));
+    }
 
-            // Split the text at underline tags
-            let mut parts: Vec<(String, bool)> = Vec::new();
-            let mut is_underlined = false;
-            let mut current_text = String::new();
+    text_element.push_str("</text>");
 
-            let mut j = 0;
-            let line_chars: Vec<char> = line.chars().collect();
+    text_element
+}
 
-            while j < line_chars.len() {
-                if j + 2 < line_chars.len() &&
-                    line_chars[j] == '<' &&
-                    line_chars[j+1] == 'u' &&
-                    line_chars[j+2] == '>' {
-                    if !current_text.is_empty() {
-                        parts.push((current_text, is_underlined));
-                        current_text = String::new();
-                    }
-                    is_underlined = true;
-                    j += 3;
-                } else if j + 3 < line_chars.len() &&
-                    line_chars[j] == '<' &&
-                    line_chars[j+1] == '/' &&
-                    line_chars[j+2] == 'u' &&
-                    line_chars[j+3] == '>' {
-                    if !current_text.is_empty() {
-                        parts.push((current_text, is_underlined));
-                        current_text = String::new();
-                    }
-                    is_underlined = false;
-                    j += 4;
-                } else {
-                    current_text.push(line_chars[j]);
-                    j += 1;
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Add any remaining text
-            if !current_text.is_empty() {
-                parts.push((current_text, is_underlined));
-            }
+    fn tag(name: &str, is_closing: bool) -> HtmlToken {
+        HtmlToken::HtmlTag { name: name.to_string(), is_closing, attributes: Vec::new() }
+    }
 
-            // Create nested tspans for each part
-            for (text_part, underlined) in parts {
-                if underlined {
-                    line_tspan.push_str(&format!("<tspan text-decoration=\"underline\">{}</tspan>", text_part));
-                } else {
-                    line_tspan.push_str(&text_part);
-                }
-            }
+    fn word(text: &str) -> HtmlToken {
+        HtmlToken::Word(text.to_string())
+    }
 
-            line_tspan.push_str("</tspan>\n  ");
-            text_element.push_str(&line_tspan);
-        } else {
-            // No underline tags in this line, add it normally
-            text_element.push_str(&format!("<tspan x=\"{}mm\" dy=\"{}mm\">{}</tspan>\n  ", center_x, dy, line));
-        }
+    /// Wraps like `wrap_text`, with every grapheme cluster one unit wide.
+    fn wrap(text: &str, max_width: f64) -> Vec<String> {
+        let width = |text: &str, _: TextStyle| text.graphemes(true).count() as f64;
+        let mut tokens = tokenize_html(text);
+        hyphenate(&mut tokens, max_width, &width, None);
+        wrap_html_tokens(&tokens, max_width, &width).unwrap().into_iter().map(html_tokens_to_string).collect()
     }
 
-    text_element.push_str("</text>");
+    #[test]
+    fn wrap_cyrillic() {
+        assert_eq!(wrap("Съешь же ещё этих мягких французских булок", 12.0), ["Съешь же ещё", "этих мягких", "французских", "булок"]);
+        assert_eq!(wrap("электроэнергия", 6.0), ["элект-", "роэне-", "ргия"]);
+    }
 
-    text_element
+    #[test]
+    fn wrap_cjk_between_characters() {
+        assert_eq!(wrap("日本語のテキストです。", 4.0), ["日本語の", "テキスト", "です。"]);
+    }
+
+    #[test]
+    fn wrap_thai_without_splitting_clusters() {
+        let text = "ภาษาไทยไม่มีช่องว่าง";
+        let lines = wrap(text, 5.0);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.graphemes(true).count() <= 5));
+        assert_eq!(lines.concat(), text);
+    }
+
+    #[test]
+    fn wrap_keeps_emoji_sequences_whole() {
+        let family = "👨\u{200d}👩\u{200d}👧\u{200d}👦";
+        assert_eq!(wrap(&family.repeat(3), 2.0), [family.repeat(2), family.to_string()]);
+    }
+
+    #[test]
+    fn wrap_unbalanced_nested_tags() {
+        assert_eq!(wrap("<b>one <i>two three</b> four</i> five", 9.0), ["<b>one <i>two</i></b>", "<b><i>three</i></b>", "<i>four</i> five"]);
+        assert_eq!(wrap("<b>a <i>b</u> c", 3.0), ["<b>a <i>b</i></b>", "<b><i>c</i></b>"]);
+    }
+
+    #[test]
+    fn balance_tags_reopens_tags_closed_out_of_order() {
+        assert_eq!(tokenize_html("<b>a<i>b</b>c</i>"), vec![
+            tag("b", false), word("a"), tag("i", false), word("b"), tag("i", true), tag("b", true),
+            tag("i", false), word("c"), tag("i", true),
+        ]);
+    }
+
+    #[test]
+    fn balance_tags_drops_stray_closing_tags_and_closes_open_ones() {
+        assert_eq!(tokenize_html("</u>a <b>b"), vec![word("a"), HtmlToken::Space, tag("b", false), word("b"), tag("b", true)]);
+    }
+
+    #[test]
+    fn line_to_svg_draws_tags_as_tspans() {
+        assert_eq!(line_to_svg("<b>a</b> <u>b</u>"), "<tspan font-weight=\"bold\">a</tspan> <tspan text-decoration=\"underline\">b</tspan>");
+        assert_eq!(line_to_svg("H<sub>2</sub>O"), "H<tspan baseline-shift=\"sub\" font-size=\"70%\">2</tspan>O");
+    }
+
+    #[test]
+    fn line_to_svg_draws_colours_and_sizes() {
+        assert_eq!(line_to_svg("<span style=\"color: #f00\">a</span>"), "<tspan fill=\"#f00\">a</tspan>");
+        assert_eq!(line_to_svg("<font size=\"-5\">a</font>"), "<tspan font-size=\"63%\">a</tspan>");
+    }
+
+    #[test]
+    fn line_to_svg_ignores_unknown_tags_and_escapes_text() {
+        assert_eq!(line_to_svg("<image href=\"x\">a &lt;b&gt; &amp;</image>"), "a &lt;b&gt; &amp;");
+        assert_eq!(line_to_svg("<i>a</i b>"), "<tspan font-style=\"italic\">a&lt;/i b&gt;</tspan>");
+    }
+
+    #[test]
+    fn line_to_svg_leaves_out_characters_xml_does_not_allow() {
+        assert_eq!(escape_xml("a\u{1}b\u{fffe}\tc"), "ab\tc");
+        assert_eq!(line_to_svg("a\u{1b}b"), "ab");
+        // References to those characters aren't decoded, so they stay as text
+        assert_eq!(line_to_svg("a&#1;b&#xFFFF;"), "a&amp;#1;b&amp;#xFFFF;");
+    }
+
+    #[test]
+    fn line_to_svg_keeps_empty_lines() {
+        assert_eq!(line_to_svg("<br>"), "\u{a0}");
+    }
+
+    #[test]
+    fn tokenize_html_keeps_less_than_signs_as_text() {
+        assert_eq!(tokenize_html("x < y"), vec![word("x"), HtmlToken::Space, word("<"), HtmlToken::Space, word("y")]);
+        assert_eq!(tokenize_html("a<b&amp;c>d"), vec![word("a<b&c>d")]);
+    }
+
+    #[test]
+    fn tokenize_html_keeps_closing_tags_with_attributes_as_text() {
+        assert_eq!(tokenize_html("<b>a</b class=\"x\">"), vec![
+            tag("b", false), word("a</"), word("b"), HtmlToken::Space, word("class=\"x\">"), tag("b", true),
+        ]);
+    }
+
+    #[test]
+    fn html_font_size_clamps_to_the_html_sizes() {
+        assert_eq!(html_font_size("-5"), Some(0.625));
+        assert_eq!(html_font_size("12"), Some(3.0));
+        assert_eq!(html_font_size("0"), Some(0.625));
+        assert_eq!(html_font_size("+9223372036854775807"), None);
+        assert_eq!(html_font_size("-9223372036854775808"), None);
+        assert_eq!(html_font_size("huge"), None);
+    }
+
+    #[test]
+    fn sanitize_leaves_allowed_text_alone() {
+        let text = "<b>bold</b> <span style=\"color: red\">red</span> &amp; <font size=\"+1\">big</font>";
+        assert_eq!(sanitize(text), (text.to_string(), Vec::new()));
+    }
+
+    #[test]
+    fn sanitize_removes_images_and_use() {
+        let (text, removed) = sanitize("a<image href=\"file:///etc/passwd\"/>b <use href=\"#x\"></use>c");
+        assert_eq!(text, "ab c");
+        assert_eq!(removed, ["<image>", "<use>"]);
+    }
+
+    #[test]
+    fn sanitize_removes_attributes_that_are_not_allowed() {
+        let (text, removed) = sanitize("<b onclick=\"x()\">a</b>");
+        assert_eq!(text, "<b>a</b>");
+        assert_eq!(removed, ["the onclick attribute of <b>"]);
+    }
+
+    #[test]
+    fn sanitize_stops_injection_through_style_and_face() {
+        let (text, removed) = sanitize("<span style=\"color: red&quot; onload=&quot;x(); fill: url(#x)\">a</span>");
+        assert_eq!(text, "<span>a</span>");
+        assert_eq!(removed, ["the style \"color: red\" onload=\"x()\"", "the style \"fill: url(#x)\""]);
+
+        let (text, removed) = sanitize("<font face='Arial\" onmouseover=\"x()'>a</font>");
+        assert_eq!(text, "<font>a</font>");
+        assert_eq!(removed, ["the face attribute of <font>"]);
+        assert!(!line_to_svg(&text).contains("onmouseover"));
+    }
+
+    #[test]
+    fn sanitize_checks_font_sizes() {
+        let (text, removed) = sanitize("<font size=\"99999999999999999999\">a</font><font size=\"+9223372036854775807\">b</font>");
+        assert_eq!(text, "<font>a</font><font>b</font>");
+        assert_eq!(removed, ["the size attribute of <font>"]);
+        assert_eq!(sanitize("<font size=\"-5\">a</font>").1, Vec::<String>::new());
+    }
 }
\ No newline at end of file